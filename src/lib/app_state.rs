@@ -1,27 +1,63 @@
+use actix::Addr;
+use actix_web::client::ClientConnector;
 use config::ProgramConfig;
+use connector::create_connector;
+use disk_cache::CacheStore;
+use har::HarRecorder;
 use options::ProgramOptions;
 use preset::RewriteFns;
+use rewrites::Matchers;
 use rjs::modules::ModuleData;
 use rjs::RequireJsClientConfig;
+use shared_state::SharedState;
+use snippets::Snippet;
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use upstream_error::UpstreamFailure;
 
 pub struct AppState {
     pub program_config: ProgramConfig,
     pub opts: ProgramOptions,
     pub rewrites: RewriteFns,
-    pub req_log: Mutex<Vec<ModuleData>>,
+    pub snippets: Vec<Snippet>,
+    ///
+    /// Shared with every other worker, see [SharedState]
+    ///
+    pub req_log: Arc<Mutex<Vec<ModuleData>>>,
     pub rjs_client_config: Arc<Mutex<RequireJsClientConfig>>,
+    pub connector: Addr<ClientConnector>,
+    pub upstream_errors: Arc<Mutex<Vec<UpstreamFailure>>>,
+    pub har: Arc<HarRecorder>,
+    pub cache: Option<CacheStore>,
+    pub matchers: Arc<Matchers>,
 }
 
 impl AppState {
     pub fn new(host: impl Into<String>, scheme: impl Into<String>) -> AppState {
+        let opts = ProgramOptions::new(host, scheme);
+        AppState::with_shared(&SharedState::default(), ProgramConfig::default(), opts)
+    }
+    ///
+    /// The state for a single worker, with its own
+    /// connector but captures shared with the others
+    ///
+    pub fn with_shared(
+        shared: &SharedState,
+        program_config: ProgramConfig,
+        opts: ProgramOptions,
+    ) -> AppState {
         AppState {
-            opts: ProgramOptions::new(host, scheme),
-            program_config: ProgramConfig::default(),
+            connector: create_connector(&opts),
+            program_config,
+            opts,
             rewrites: vec![],
-            req_log: Mutex::new(vec![]),
-            rjs_client_config: Arc::new(Mutex::new(RequireJsClientConfig::default())),
+            snippets: vec![],
+            req_log: shared.req_log.clone(),
+            rjs_client_config: shared.rjs_client_config.clone(),
+            upstream_errors: shared.upstream_errors.clone(),
+            har: shared.har.clone(),
+            cache: shared.cache.clone(),
+            matchers: Arc::new(Matchers::default()),
         }
     }
 }
@@ -34,13 +70,20 @@ impl fmt::Debug for AppState {
     program_config: {:?},
     opts: {:?},
     rewrites: {} rewrite fns,
-    req_log: Mutex<Vec<ModuleData>>,
-    rjs_client_config: Arc<Mutex<RequireJsClientConfig>>
+    snippets: {:?},
+    req_log: Arc<Mutex<Vec<ModuleData>>>,
+    rjs_client_config: Arc<Mutex<RequireJsClientConfig>>,
+    connector: Addr<ClientConnector>,
+    upstream_errors: Arc<Mutex<Vec<UpstreamFailure>>>,
+    har: Arc<HarRecorder>,
+    cache: Option<CacheStore>,
+    matchers: Arc<Matchers>
 }}
         ",
             self.program_config,
             self.opts,
-            self.rewrites.len()
+            self.rewrites.len(),
+            self.snippets
         )
     }
 }
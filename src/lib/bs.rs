@@ -2,8 +2,11 @@
 extern crate actix;
 extern crate actix_web;
 extern crate base64;
+extern crate brotli2;
 extern crate bytes;
 extern crate clap;
+extern crate encoding_rs;
+extern crate flate2;
 
 #[macro_use]
 extern crate from_file_derive;
@@ -29,22 +32,45 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate tempdir;
+extern crate tokio_io;
+extern crate tokio_tcp;
 
 extern crate rjs;
 
 pub mod app_state;
+pub mod charset;
 pub mod config;
+pub mod connector;
+pub mod content_encoding;
+pub mod cookies;
+pub mod csp;
+pub mod disk_cache;
+pub mod glob;
+pub mod har;
+pub mod header_rules;
 pub mod headers;
 pub mod options;
+pub mod overrides;
 pub mod preset;
 pub mod presets;
+pub mod proxy_response;
 pub mod proxy_transform;
 pub mod proxy_utils;
+pub mod regex_rewrites;
 pub mod replacer;
+pub mod replay;
+pub mod rewrite_rules;
+pub mod rewrite_stream;
 pub mod rewrites;
 pub mod setup;
+pub mod shared_state;
+pub mod snippets;
 pub mod ssl;
 pub mod system;
 pub mod test_utils;
+pub mod upstream_error;
+pub mod upstream_proxy;
+pub mod upstreams;
 pub mod with_body;
 pub mod without_body;
+pub mod ws_proxy;
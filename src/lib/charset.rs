@@ -0,0 +1,166 @@
+use actix_web::http::{header, HeaderMap};
+use encoding_rs::{Encoding, UTF_8};
+use regex::bytes::Regex;
+
+///
+/// How many bytes of a body are searched for a `<meta charset>`,
+/// this mirrors the limit used by browsers
+///
+pub const SNIFF_LIMIT: usize = 1024;
+
+///
+/// The character encoding of a response body, as
+/// declared in its `Content-Type` header
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Charset {
+    Declared(&'static Encoding),
+    Undeclared,
+    ///
+    /// Either the label is unknown, or it's an encoding
+    /// (like UTF-16) that can't be rewritten line by line
+    ///
+    Unsupported,
+}
+
+impl Charset {
+    pub fn from_headers(headers: &HeaderMap) -> Charset {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Charset::Undeclared, Charset::from_content_type)
+    }
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate bs;
+    /// # extern crate encoding_rs;
+    /// # use bs::charset::*;
+    /// # use encoding_rs::WINDOWS_1252;
+    /// assert_eq!(
+    ///     Charset::from_content_type("text/html; charset=ISO-8859-1"),
+    ///     Charset::Declared(WINDOWS_1252)
+    /// );
+    /// assert_eq!(Charset::from_content_type("text/html"), Charset::Undeclared);
+    /// assert_eq!(Charset::from_content_type("text/html; charset=utf-16"), Charset::Unsupported);
+    /// ```
+    ///
+    pub fn from_content_type(value: &str) -> Charset {
+        let label = value.split(';').skip(1).find_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("charset") => {
+                    Some(v.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+                }
+                _ => None,
+            }
+        });
+        match label {
+            Some(label) => match Encoding::for_label(label.as_bytes()) {
+                Some(encoding) if encoding.is_ascii_compatible() => Charset::Declared(encoding),
+                _ => Charset::Unsupported,
+            },
+            None => Charset::Undeclared,
+        }
+    }
+}
+
+///
+/// Look for a `<meta charset="...">` or `<meta http-equiv="Content-Type" content="...; charset=...">`
+/// within the first [SNIFF_LIMIT] bytes of a body
+///
+pub fn from_meta(bytes: &[u8]) -> Option<&'static Encoding> {
+    let limit = bytes.len().min(SNIFF_LIMIT);
+    META_CHARSET.with(|regex| {
+        regex
+            .captures(&bytes[..limit])
+            .and_then(|caps| caps.get(1))
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+    })
+}
+
+thread_local! {
+    ///
+    /// Compiled once for each worker, since every undeclared body is sniffed
+    ///
+    static META_CHARSET: Regex =
+        Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?([a-z0-9_\-:.]+)"#).expect("valid regex");
+}
+
+///
+/// Resolve the encoding to use for a body where nothing was declared
+/// in the headers. `None` means the body should not be touched
+///
+pub fn sniff(bytes: &[u8]) -> Option<&'static Encoding> {
+    match from_meta(bytes) {
+        Some(encoding) if encoding.is_ascii_compatible() => Some(encoding),
+        Some(..) => None,
+        None => Some(UTF_8),
+    }
+}
+
+///
+/// Decode an entire body, apply `f` to the text and then encode it back again.
+///
+/// Bodies that cannot be decoded are returned untouched
+///
+pub fn transform_text<F>(headers: &HeaderMap, bytes: Vec<u8>, f: F) -> Vec<u8>
+where
+    F: Fn(String) -> String,
+{
+    let encoding = match Charset::from_headers(headers) {
+        Charset::Declared(encoding) => Some(encoding),
+        Charset::Undeclared => sniff(&bytes),
+        Charset::Unsupported => None,
+    };
+    let text = encoding.and_then(|encoding| {
+        encoding
+            .decode_without_bom_handling_and_without_replacement(&bytes)
+            .map(|text| (encoding, text.into_owned()))
+    });
+    match text {
+        Some((encoding, text)) => encoding.encode(&f(text)).0.into_owned(),
+        None => bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    #[test]
+    fn test_from_meta() {
+        let html = br#"<html><head><meta charset="windows-1252"></head>"#;
+        assert_eq!(from_meta(html), Some(WINDOWS_1252));
+
+        let html = br#"<meta http-equiv="Content-Type" content="text/html; charset=iso-8859-1">"#;
+        assert_eq!(from_meta(html), Some(WINDOWS_1252));
+
+        assert_eq!(from_meta(b"<html><head></head>"), None);
+    }
+
+    #[test]
+    fn test_transform_latin1() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "text/html; charset=iso-8859-1".parse().unwrap(),
+        );
+        // "café" in ISO-8859-1
+        let input = b"caf\xe9 https://www.acme.com".to_vec();
+        let output = transform_text(&headers, input, |s| s.replace("www.acme.com", "127.0.0.1"));
+        assert_eq!(output, b"caf\xe9 https://127.0.0.1".to_vec());
+    }
+
+    #[test]
+    fn test_transform_invalid_passes_through() {
+        let headers = HeaderMap::new();
+        let input = b"caf\xe9 https://www.acme.com".to_vec();
+        let output = transform_text(&headers, input.clone(), |s| {
+            s.replace("www.acme.com", "127.0.0.1")
+        });
+        assert_eq!(output, input);
+    }
+}
@@ -2,20 +2,102 @@ extern crate serde_json;
 extern crate serde_yaml;
 
 use clap::Error;
+use cookies::CookieRules;
+use csp::CspMode;
+use disk_cache::CacheConfig;
 use from_file::FromFile;
 use from_file::FromFileError;
+use header_rules::HeaderRules;
 use options::ConfigError;
+use overrides::Override;
+use regex_rewrites::RegexRewrite;
+use rewrite_rules::{default_rewrite_rules, RewriteRule};
 use serde_json::Value;
+use snippets::Snippet;
 use std;
+use upstream_proxy::UpstreamProxy;
+use upstreams::ExtraUpstream;
 
 #[derive(Deserialize, FromFile, Debug, Clone)]
 pub struct ProgramConfig {
     pub presets: Vec<PresetConfig>,
+
+    #[serde(default = "default_rewrite_rules")]
+    pub rewrite_rules: Vec<RewriteRule>,
+
+    ///
+    /// Find & replace rules for response bodies, applied
+    /// after links to the target have been rewritten
+    ///
+    #[serde(default)]
+    pub rewrites: Vec<RegexRewrite>,
+
+    ///
+    /// Used when `--upstream-proxy` is not given
+    ///
+    #[serde(default)]
+    pub upstream_proxy: Option<UpstreamProxy>,
+
+    ///
+    /// Extra hosts (CDNs, media subdomains etc) that are
+    /// proxied under `/__bs/upstream/<name>`
+    ///
+    #[serde(default)]
+    pub upstreams: Vec<ExtraUpstream>,
+
+    ///
+    /// How `Set-Cookie` headers from the target are rewritten
+    ///
+    #[serde(default)]
+    pub cookies: CookieRules,
+
+    ///
+    /// Headers to add, set or remove on requests & responses
+    ///
+    #[serde(default)]
+    pub headers: HeaderRules,
+
+    ///
+    /// Whether Content-Security-Policy headers are rewritten,
+    /// removed or left alone
+    ///
+    #[serde(default)]
+    pub csp: CspMode,
+
+    ///
+    /// Keep static assets from the target on disk
+    ///
+    #[serde(default)]
+    pub cache: CacheConfig,
+
+    ///
+    /// Local files that are served in place of the target's responses
+    ///
+    #[serde(default)]
+    pub overrides: Vec<Override>,
+
+    ///
+    /// Markup to add to every proxied HTML page
+    ///
+    #[serde(default)]
+    pub snippets: Vec<Snippet>,
 }
 
 impl Default for ProgramConfig {
     fn default() -> ProgramConfig {
-        ProgramConfig { presets: vec![] }
+        ProgramConfig {
+            presets: vec![],
+            rewrite_rules: default_rewrite_rules(),
+            rewrites: vec![],
+            upstream_proxy: None,
+            upstreams: vec![],
+            cookies: CookieRules::default(),
+            headers: HeaderRules::default(),
+            csp: CspMode::default(),
+            cache: CacheConfig::default(),
+            overrides: vec![],
+            snippets: vec![],
+        }
     }
 }
 
@@ -32,6 +114,7 @@ impl ProgramConfig {
                 name: "m2".into(),
                 options: json!({}),
             }],
+            ..ProgramConfig::default()
         }
     }
 }
@@ -59,6 +142,9 @@ pub enum ProgramStartError {
     SslFailed,
     SslTempDir,
     SslTempDirClose,
+    UpstreamTls(String),
+    Replay(String),
+    HarFile(std::io::Error),
 }
 
 impl std::fmt::Display for ProgramStartError {
@@ -80,6 +166,21 @@ impl std::fmt::Display for ProgramStartError {
             ProgramStartError::ConfigCliError(ConfigError::TimeoutInvalid) => {
                 write!(f, "{}", ConfigError::TimeoutInvalid)
             }
+            ProgramStartError::ConfigCliError(ConfigError::ConnOptionInvalid(name)) => {
+                write!(f, "{}", ConfigError::ConnOptionInvalid(*name))
+            }
+            ProgramStartError::ConfigCliError(ConfigError::WorkersInvalid) => {
+                write!(f, "{}", ConfigError::WorkersInvalid)
+            }
+            ProgramStartError::ConfigCliError(e @ ConfigError::UpstreamProxyInvalid(..)) => {
+                write!(f, "{}", e)
+            }
+            ProgramStartError::ConfigCliError(e @ ConfigError::HostInvalid(..)) => {
+                write!(f, "{}", e)
+            }
+            ProgramStartError::ConfigCliError(e @ ConfigError::ListenInvalid(..)) => {
+                write!(f, "{}", e)
+            }
             ProgramStartError::ConfigFileOpen => write!(f, "config file not found"),
             ProgramStartError::ConfigFileRead => write!(f, "config file content could not be read"),
             ProgramStartError::FromFile(e) => write!(f, "{}", e),
@@ -90,6 +191,19 @@ impl std::fmt::Display for ProgramStartError {
                 "could not create the temp dir to hold self-signed ssl certs"
             ),
             ProgramStartError::SslTempDirClose => write!(f, "could not clean up the temp dir"),
+            ProgramStartError::UpstreamTls(e) => write!(
+                f,
+                "could not set up TLS for connecting to the target, reason: {}",
+                e
+            ),
+            ProgramStartError::Replay(e) => write!(f, "could not replay the recording, {}", e),
+            ProgramStartError::HarFile(e) => {
+                write!(
+                    f,
+                    "could not create the file for --record-har, reason: {}",
+                    e
+                )
+            }
             ProgramStartError::BindHttp(e) => write!(f, "could not bind over http, reason: {}", e),
             ProgramStartError::BindHttps(e) => {
                 write!(f, "could not bind over https, reason: {}", e)
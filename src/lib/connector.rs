@@ -0,0 +1,126 @@
+use actix::{Actor, Addr};
+use actix_web::client::ClientConnector;
+use config::ProgramStartError;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use options::{ProgramOptions, UpstreamTls};
+use replay::ReplayResolver;
+use std::time::Duration;
+use upstream_proxy::ProxyResolver;
+
+///
+/// Create the pool of connections that's used for every
+/// request to the proxy target.
+///
+/// This starts an actor, so it must be called from within a running
+/// system - each worker creates its own when building the app state,
+/// with an equal share of the connection limits
+///
+pub fn create_connector(opts: &ProgramOptions) -> Addr<ClientConnector> {
+    let ssl_conn = ssl_connector(&opts.upstream_tls)
+        .expect("upstream TLS options are validated before the server starts");
+
+    let connector = ClientConnector::with_connector(ssl_conn)
+        .limit(per_worker(opts.conn_limit, opts.workers))
+        .limit_per_host(per_worker(opts.conn_limit_per_host, opts.workers))
+        .conn_keep_alive(Duration::from_secs(opts.conn_keep_alive_secs));
+
+    // When there's an upstream proxy, connections are made through
+    // it instead of directly - TLS and pooling work the same either way.
+    // A recording being replayed takes the place of the target entirely
+    let connector = match (opts.replay_addr, &opts.upstream_proxy) {
+        (Some(addr), _) => connector.resolver(ReplayResolver::new(addr).start().recipient()),
+        (None, Some(proxy)) => {
+            connector.resolver(ProxyResolver::new(proxy.clone()).start().recipient())
+        }
+        (None, None) => connector,
+    };
+
+    connector.start()
+}
+
+///
+/// A worker's share of a connection limit, so that the flags cover every
+/// worker together. Each one can always open at least one connection
+///
+fn per_worker(limit: usize, workers: usize) -> usize {
+    match limit {
+        0 => 0,
+        limit => (limit / workers.max(1)).max(1),
+    }
+}
+
+///
+/// Build the TLS settings for connecting to the proxy target.
+///
+/// Since this is a development tool, certificates are not verified
+/// unless asked for. Any files given must exist and contain PEM data
+///
+pub fn ssl_connector(tls: &UpstreamTls) -> Result<SslConnector, ProgramStartError> {
+    let tls_error = |name: &str, path: &str, e: ErrorStack| {
+        ProgramStartError::UpstreamTls(format!("{} `{}` could not be used, {}", name, path, e))
+    };
+
+    let mut ssl_conn = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| ProgramStartError::UpstreamTls(e.to_string()))?;
+
+    if tls.should_verify() {
+        ssl_conn.set_verify(SslVerifyMode::PEER);
+    } else {
+        ssl_conn.set_verify(SslVerifyMode::NONE);
+    }
+
+    if let Some(ref ca_file) = tls.ca_file {
+        ssl_conn
+            .set_ca_file(ca_file)
+            .map_err(|e| tls_error("CA bundle", ca_file, e))?;
+    }
+
+    if let Some(ref client_cert) = tls.client_cert {
+        // the key may live in the same PEM file as the certificate
+        let client_key = tls.client_key.as_ref().unwrap_or(client_cert);
+        ssl_conn
+            .set_certificate_chain_file(client_cert)
+            .map_err(|e| tls_error("client certificate", client_cert, e))?;
+        ssl_conn
+            .set_private_key_file(client_key, SslFiletype::PEM)
+            .map_err(|e| tls_error("client key", client_key, e))?;
+        ssl_conn
+            .check_private_key()
+            .map_err(|e| tls_error("client key", client_key, e))?;
+    }
+
+    Ok(ssl_conn.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_ca_file() {
+        let tls = UpstreamTls {
+            ca_file: Some("test/fixtures/missing-ca.pem".into()),
+            ..UpstreamTls::default()
+        };
+        match ssl_connector(&tls) {
+            Err(ProgramStartError::UpstreamTls(e)) => {
+                assert!(e.starts_with("CA bundle `test/fixtures/missing-ca.pem` could not be used"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_default_tls() {
+        assert!(ssl_connector(&UpstreamTls::default()).is_ok());
+    }
+
+    #[test]
+    fn test_per_worker() {
+        assert_eq!(per_worker(100, 1), 100);
+        assert_eq!(per_worker(100, 4), 25);
+        assert_eq!(per_worker(2, 4), 1);
+        assert_eq!(per_worker(0, 4), 0);
+    }
+}
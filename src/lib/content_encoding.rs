@@ -0,0 +1,189 @@
+use actix_web::http::{header, HeaderMap};
+use brotli2::write::{BrotliDecoder, BrotliEncoder};
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+use std::io;
+use std::io::Write;
+use std::mem;
+
+///
+/// The content-codings that can be decoded before
+/// rewriting, and re-applied afterwards
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentCoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Br,
+}
+
+impl ContentCoding {
+    ///
+    /// Read the `Content-Encoding` of a response.
+    ///
+    /// `None` means the body uses a coding (or a combination of codings)
+    /// that cannot be handled here, and so must not be touched
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate actix_web;
+    /// # extern crate bs;
+    /// # use actix_web::http::HeaderMap;
+    /// # use bs::content_encoding::*;
+    /// let mut headers = HeaderMap::new();
+    /// assert_eq!(ContentCoding::from_headers(&headers), Some(ContentCoding::Identity));
+    ///
+    /// headers.insert("content-encoding", "gzip".parse().unwrap());
+    /// assert_eq!(ContentCoding::from_headers(&headers), Some(ContentCoding::Gzip));
+    ///
+    /// headers.insert("content-encoding", "gzip, br".parse().unwrap());
+    /// assert_eq!(ContentCoding::from_headers(&headers), None);
+    /// ```
+    ///
+    pub fn from_headers(headers: &HeaderMap) -> Option<ContentCoding> {
+        match headers.get(header::CONTENT_ENCODING) {
+            None => Some(ContentCoding::Identity),
+            Some(value) => {
+                let value = value.to_str().ok()?.trim().to_lowercase();
+                match value.as_str() {
+                    "" | "identity" => Some(ContentCoding::Identity),
+                    "gzip" | "x-gzip" => Some(ContentCoding::Gzip),
+                    "deflate" => Some(ContentCoding::Deflate),
+                    "br" => Some(ContentCoding::Br),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+///
+/// A streaming encoder or decoder for a single [ContentCoding].
+///
+/// Each call to `write` returns whatever output is available so far,
+/// `finish` returns anything that's left
+///
+pub enum Coder {
+    Identity,
+    GzipDecode(GzDecoder<Vec<u8>>),
+    GzipEncode(GzEncoder<Vec<u8>>),
+    DeflateDecode(ZlibDecoder<Vec<u8>>),
+    DeflateEncode(ZlibEncoder<Vec<u8>>),
+    BrDecode(BrotliDecoder<Vec<u8>>),
+    BrEncode(BrotliEncoder<Vec<u8>>),
+}
+
+impl Coder {
+    pub fn decoder(coding: ContentCoding) -> Coder {
+        match coding {
+            ContentCoding::Identity => Coder::Identity,
+            ContentCoding::Gzip => Coder::GzipDecode(GzDecoder::new(vec![])),
+            ContentCoding::Deflate => Coder::DeflateDecode(ZlibDecoder::new(vec![])),
+            ContentCoding::Br => Coder::BrDecode(BrotliDecoder::new(vec![])),
+        }
+    }
+    pub fn encoder(coding: ContentCoding) -> Coder {
+        match coding {
+            ContentCoding::Identity => Coder::Identity,
+            ContentCoding::Gzip => Coder::GzipEncode(GzEncoder::new(vec![], Compression::fast())),
+            ContentCoding::Deflate => {
+                Coder::DeflateEncode(ZlibEncoder::new(vec![], Compression::fast()))
+            }
+            ContentCoding::Br => Coder::BrEncode(BrotliEncoder::new(vec![], 3)),
+        }
+    }
+    pub fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        // decoders are flushed on every write so that
+        // decoded content is available to the rewriters straight away
+        let output = match *self {
+            Coder::Identity => return Ok(chunk.to_vec()),
+            Coder::GzipDecode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.flush()?;
+                c.get_mut()
+            }
+            Coder::DeflateDecode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.flush()?;
+                c.get_mut()
+            }
+            Coder::BrDecode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.flush()?;
+                c.get_mut()
+            }
+            Coder::GzipEncode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.get_mut()
+            }
+            Coder::DeflateEncode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.get_mut()
+            }
+            Coder::BrEncode(ref mut c) => {
+                c.write_all(chunk)?;
+                c.get_mut()
+            }
+        };
+        Ok(mem::replace(output, vec![]))
+    }
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Coder::Identity => Ok(vec![]),
+            Coder::GzipDecode(c) => c.finish(),
+            Coder::GzipEncode(c) => c.finish(),
+            Coder::DeflateDecode(c) => c.finish(),
+            Coder::DeflateEncode(c) => c.finish(),
+            Coder::BrDecode(c) => c.finish(),
+            Coder::BrEncode(c) => c.finish(),
+        }
+    }
+}
+
+///
+/// Decode a complete, buffered body
+///
+pub fn decode_body(coding: ContentCoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = Coder::decoder(coding);
+    let mut output = decoder.write(body)?;
+    output.extend(decoder.finish()?);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(coding: ContentCoding) {
+        let input = b"<a href=\"https://www.acme.com\">Home</a>\n".repeat(100);
+        let mut encoder = Coder::encoder(coding);
+        let mut encoded = encoder.write(&input).unwrap();
+        encoded.extend(encoder.finish().unwrap());
+
+        // feed the decoder in small pieces, like a network stream would
+        let mut decoder = Coder::decoder(coding);
+        let mut decoded = vec![];
+        for chunk in encoded.chunks(7) {
+            decoded.extend(decoder.write(chunk).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_round_trip_gzip() {
+        round_trip(ContentCoding::Gzip);
+    }
+
+    #[test]
+    fn test_round_trip_deflate() {
+        round_trip(ContentCoding::Deflate);
+    }
+
+    #[test]
+    fn test_round_trip_br() {
+        round_trip(ContentCoding::Br);
+    }
+}
@@ -0,0 +1,207 @@
+use actix_web::HttpRequest;
+use app_state::AppState;
+use upstreams::find_upstream;
+
+///
+/// Which attributes of `Set-Cookie` headers are rewritten,
+/// every rule is on by default
+///
+/// # Examples
+///
+/// ```yaml
+/// cookies:
+///   same_site: false
+/// ```
+///
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CookieRules {
+    ///
+    /// Remove the `Domain`, so that cookies are kept for the local host
+    ///
+    pub domain: bool,
+    ///
+    /// Only keep `Secure` when the local server is on https
+    ///
+    pub secure: bool,
+    ///
+    /// `SameSite=None` is only accepted alongside `Secure`, so it's
+    /// relaxed to `Lax` on http, or given `Secure` on https
+    ///
+    pub same_site: bool,
+    ///
+    /// Prefix the `Path` of cookies from an extra upstream with
+    /// the local path it's mounted on
+    ///
+    pub path: bool,
+}
+
+impl Default for CookieRules {
+    fn default() -> CookieRules {
+        CookieRules {
+            domain: true,
+            secure: true,
+            same_site: true,
+            path: true,
+        }
+    }
+}
+
+///
+/// Everything needed to rewrite the cookies
+/// in a single response
+///
+#[derive(Debug, Clone, Default)]
+pub struct CookieRewrite {
+    pub rules: CookieRules,
+    ///
+    /// Whether the local server is being accessed over https
+    ///
+    pub secure: bool,
+    ///
+    /// The local path that the responding host is mounted on,
+    /// empty for the proxy target itself
+    ///
+    pub path_prefix: String,
+}
+
+impl CookieRewrite {
+    pub fn from_request(req: &HttpRequest<AppState>) -> CookieRewrite {
+        let state = req.state();
+        let path_prefix = find_upstream(&state.program_config.upstreams, req.path())
+            .map(|(upstream, _)| upstream.prefix())
+            .unwrap_or_default();
+        CookieRewrite {
+            rules: state.program_config.cookies.clone(),
+            secure: req.connection_info().scheme() == "https",
+            path_prefix,
+        }
+    }
+
+    ///
+    /// Rewrite the value of a single `Set-Cookie` header.
+    ///
+    /// Attributes are handled as plain text, so that any
+    /// that aren't understood are passed through untouched
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bs::cookies::CookieRewrite;
+    ///
+    /// let rewrite = CookieRewrite::default();
+    /// assert_eq!(
+    ///     rewrite.rewrite("form_key=123; Domain=www.acme.com; Secure; SameSite=None; HttpOnly"),
+    ///     "form_key=123; Domain=; SameSite=Lax; HttpOnly"
+    /// );
+    ///
+    /// let rewrite = CookieRewrite { secure: true, ..CookieRewrite::default() };
+    /// assert_eq!(
+    ///     rewrite.rewrite("form_key=123; SameSite=None"),
+    ///     "form_key=123; SameSite=None; Secure"
+    /// );
+    /// ```
+    ///
+    pub fn rewrite(&self, value: &str) -> String {
+        let mut parts = value.split(';').map(str::trim).filter(|p| !p.is_empty());
+
+        let pair = match parts.next() {
+            Some(pair) => pair,
+            None => return value.to_string(),
+        };
+
+        let mut attrs = vec![pair.to_string()];
+        let mut secure = false;
+        let mut same_site_none = false;
+
+        for attr in parts {
+            let (name, attr_value) = match attr.find('=') {
+                Some(index) => (&attr[..index], attr[index + 1..].trim()),
+                None => (attr, ""),
+            };
+            match name.trim().to_lowercase().as_str() {
+                "domain" if self.rules.domain => attrs.push("Domain=".to_string()),
+                "secure" => secure = true,
+                "samesite" if self.rules.same_site && attr_value.eq_ignore_ascii_case("none") => {
+                    same_site_none = true;
+                    attrs.push(match self.secure {
+                        true => "SameSite=None".to_string(),
+                        false => "SameSite=Lax".to_string(),
+                    });
+                }
+                "path" if self.rules.path && !self.path_prefix.is_empty() => {
+                    attrs.push(format!("Path={}", self.prefixed_path(attr_value)))
+                }
+                _ => attrs.push(attr.to_string()),
+            }
+        }
+
+        let secure = match self.rules.secure {
+            true => secure && self.secure,
+            false => secure,
+        };
+
+        // browsers reject `SameSite=None` without `Secure`
+        if secure || (same_site_none && self.secure) {
+            attrs.push("Secure".to_string());
+        }
+
+        attrs.join("; ")
+    }
+
+    fn prefixed_path(&self, path: &str) -> String {
+        match path.trim_end_matches('/') {
+            "" => self.path_prefix.clone(),
+            path => format!("{}{}", self.path_prefix, path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_path() {
+        let rewrite = CookieRewrite {
+            path_prefix: "/__bs/upstream/static".into(),
+            ..CookieRewrite::default()
+        };
+        assert_eq!(
+            rewrite.rewrite("a=b; Path=/"),
+            "a=b; Path=/__bs/upstream/static"
+        );
+        assert_eq!(
+            rewrite.rewrite("a=b; path=/media/"),
+            "a=b; Path=/__bs/upstream/static/media"
+        );
+    }
+
+    #[test]
+    fn test_rules_can_be_disabled() {
+        let rewrite = CookieRewrite {
+            rules: CookieRules {
+                secure: false,
+                same_site: false,
+                ..CookieRules::default()
+            },
+            ..CookieRewrite::default()
+        };
+        assert_eq!(
+            rewrite.rewrite("a=b; Secure; SameSite=None"),
+            "a=b; SameSite=None; Secure"
+        );
+    }
+
+    #[test]
+    fn test_keeps_secure_on_https() {
+        let rewrite = CookieRewrite {
+            secure: true,
+            ..CookieRewrite::default()
+        };
+        assert_eq!(
+            rewrite.rewrite("a=b; Secure; HttpOnly"),
+            "a=b; HttpOnly; Secure"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use std::iter;
+use upstreams::ExtraUpstream;
+
+///
+/// What happens to `Content-Security-Policy` (and `-Report-Only`)
+/// headers sent by the target
+///
+/// # Examples
+///
+/// ```yaml
+/// csp: strip
+/// ```
+///
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CspMode {
+    ///
+    /// Point every source that names the target (or an extra
+    /// upstream) at the local server
+    ///
+    Rewrite,
+    ///
+    /// Remove the headers entirely
+    ///
+    Strip,
+    ///
+    /// Pass the headers through untouched
+    ///
+    Keep,
+}
+
+impl Default for CspMode {
+    fn default() -> CspMode {
+        CspMode::Rewrite
+    }
+}
+
+///
+/// Directives that stop a page served over `http` from loading anything
+///
+const HTTPS_ONLY_DIRECTIVES: [&str; 2] = ["upgrade-insecure-requests", "block-all-mixed-content"];
+
+///
+/// Swap sources for `target` (a host, with an optional port) for the
+/// local `origin` in every directive of a policy. Sources for any of the
+/// `upstreams` are pointed at their prefix on the local origin instead.
+///
+/// Wildcard sources that cover the target (or an upstream) are kept,
+/// with the local origin added alongside them
+///
+/// # Examples
+///
+/// ```
+/// use bs::csp::rewrite_policy;
+/// use bs::upstreams::ExtraUpstream;
+///
+/// let policy = "default-src 'self' https://www.acme.com:443; script-src *.acme.com 'unsafe-eval'; report-uri https://www.acme.com/csp";
+/// let expected = "default-src 'self' http://127.0.0.1:8080; script-src *.acme.com 'unsafe-eval' http://127.0.0.1:8080; report-uri http://127.0.0.1:8080/csp";
+///
+/// assert_eq!(rewrite_policy(policy, "www.acme.com", "http://127.0.0.1:8080", &[]), expected);
+///
+/// let upstreams = vec![ExtraUpstream::new("media", "https://media.acme.com").unwrap()];
+/// assert_eq!(
+///     rewrite_policy("img-src https://media.acme.com", "www.acme.com", "http://127.0.0.1:8080", &upstreams),
+///     "img-src http://127.0.0.1:8080/__bs/upstream/media/"
+/// );
+/// ```
+///
+pub fn rewrite_policy(
+    policy: &str,
+    target: &str,
+    origin: &str,
+    upstreams: &[ExtraUpstream],
+) -> String {
+    let target = target.to_lowercase();
+    let (target_name, _) = split_port(&target);
+    let upstreams = upstreams
+        .iter()
+        .map(|upstream| {
+            (
+                upstream.host.to_lowercase(),
+                format!("{}{}", origin, upstream.prefix()),
+            )
+        })
+        .collect::<Vec<(String, String)>>();
+    let is_http = origin.starts_with("http://");
+
+    policy
+        .split(';')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| {
+            let mut tokens = directive.split_whitespace();
+            let name = tokens.next()?;
+
+            if is_http && HTTPS_ONLY_DIRECTIVES.contains(&name.to_lowercase().as_str()) {
+                return None;
+            }
+
+            let mut add_origin = false;
+            let mut sources: Vec<String> = tokens
+                .map(|source| {
+                    let s = match Source::parse(source) {
+                        Some(s) => s,
+                        None => return source.to_string(),
+                    };
+                    if s.is_for(&target) {
+                        return format!("{}{}", origin, s.path);
+                    }
+                    // the prefix alone would only allow that exact path
+                    if let Some((_, local)) = upstreams.iter().find(|(host, _)| s.is_for(host)) {
+                        return match s.path {
+                            "" => format!("{}/", local),
+                            path => format!("{}{}", local, path),
+                        };
+                    }
+                    let mut names = iter::once(target_name)
+                        .chain(upstreams.iter().map(|(host, _)| split_port(host).0));
+                    if names.any(|name| s.is_wildcard_for(name)) {
+                        add_origin = true;
+                    }
+                    source.to_string()
+                })
+                .collect();
+
+            if add_origin && !sources.iter().any(|s| s == origin) {
+                sources.push(origin.to_string());
+            }
+
+            Some(
+                iter::once(name.to_string())
+                    .chain(sources)
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+///
+/// Split a host into its name & port, when it has one
+///
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    match host.rfind(':') {
+        Some(index)
+            if &host[index + 1..] == "*" || host[index + 1..].chars().all(|c| c.is_digit(10)) =>
+        {
+            (&host[..index], Some(&host[index + 1..]))
+        }
+        _ => (host, None),
+    }
+}
+
+///
+/// The parts of a host-source that matter when rewriting
+///
+#[derive(Debug, PartialEq)]
+struct Source<'a> {
+    ///
+    /// Lowercase, without the `://`
+    ///
+    scheme: Option<String>,
+    ///
+    /// Lowercase, without the port
+    ///
+    host: String,
+    port: Option<&'a str>,
+    path: &'a str,
+}
+
+impl<'a> Source<'a> {
+    fn parse(source: &'a str) -> Option<Source<'a>> {
+        // keywords, nonces, hashes and scheme-only sources
+        if source.starts_with('\'') || source.ends_with(':') {
+            return None;
+        }
+        let (scheme, without_scheme) = match source.find("://") {
+            Some(index) => (Some(source[..index].to_lowercase()), &source[index + 3..]),
+            None => (None, source),
+        };
+        let (host, path) = match without_scheme.find('/') {
+            Some(index) => without_scheme.split_at(index),
+            None => (without_scheme, ""),
+        };
+        let (host, port) = split_port(host);
+        match host.is_empty() {
+            true => None,
+            false => Some(Source {
+                scheme,
+                host: host.to_lowercase(),
+                port,
+                path,
+            }),
+        }
+    }
+
+    ///
+    /// Does this source name `host` (with an optional port)? A port given
+    /// for just one of them has to be the default for the source's scheme
+    ///
+    fn is_for(&self, host: &str) -> bool {
+        let (name, port) = split_port(host);
+        if self.host != name {
+            return false;
+        }
+        match (self.port, port) {
+            (None, None) | (Some("*"), _) => true,
+            (Some(port), Some(other)) => port == other,
+            (Some(port), None) => match self.scheme.as_ref().map(String::as_str) {
+                Some("http") | Some("ws") => port == "80",
+                Some("https") | Some("wss") => port == "443",
+                _ => port == "80" || port == "443",
+            },
+            (None, Some(..)) => false,
+        }
+    }
+    fn is_wildcard_for(&self, host_name: &str) -> bool {
+        self.host.starts_with("*.") && host_name.ends_with(&self.host[1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_policy_with_port() {
+        let policy = "img-src www.acme.com:8443 www.acme.com data:; upgrade-insecure-requests";
+        assert_eq!(
+            rewrite_policy(policy, "www.acme.com:8443", "https://127.0.0.1:8080", &[]),
+            "img-src https://127.0.0.1:8080 www.acme.com data:; upgrade-insecure-requests"
+        );
+        assert_eq!(
+            rewrite_policy(policy, "www.acme.com:8443", "http://127.0.0.1:8080", &[]),
+            "img-src http://127.0.0.1:8080 www.acme.com data:"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_policy_with_default_ports() {
+        let policy = "img-src https://www.acme.com:443 http://www.acme.com:80 https://www.acme.com:80 www.acme.com:* https://www.acme.com:8443";
+        assert_eq!(
+            rewrite_policy(policy, "www.acme.com", "http://127.0.0.1:8080", &[]),
+            "img-src http://127.0.0.1:8080 http://127.0.0.1:8080 https://www.acme.com:80 http://127.0.0.1:8080 https://www.acme.com:8443"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_policy_with_upstreams() {
+        let upstreams = vec![
+            ExtraUpstream::new("media", "https://media.acme.com").unwrap(),
+            ExtraUpstream::new("cdn", "https://cdn.acme-assets.net:8443").unwrap(),
+        ];
+        let policy = "img-src https://media.acme.com/catalog/ media.acme.com:443; script-src https://cdn.acme-assets.net:8443 *.acme-assets.net";
+        assert_eq!(
+            rewrite_policy(policy, "www.acme.com", "http://127.0.0.1:8080", &upstreams),
+            "img-src http://127.0.0.1:8080/__bs/upstream/media/catalog/ http://127.0.0.1:8080/__bs/upstream/media/; \
+             script-src http://127.0.0.1:8080/__bs/upstream/cdn/ *.acme-assets.net http://127.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn test_parse_sources() {
+        assert_eq!(Source::parse("'self'"), None);
+        assert_eq!(Source::parse("https:"), None);
+        assert_eq!(
+            Source::parse("https://WWW.acme.com:443/static/"),
+            Some(Source {
+                scheme: Some("https".into()),
+                host: "www.acme.com".into(),
+                port: Some("443"),
+                path: "/static/"
+            })
+        );
+        assert!(Source::parse("*.acme.com")
+            .unwrap()
+            .is_wildcard_for("www.acme.com"));
+        assert!(!Source::parse("*.acme.com")
+            .unwrap()
+            .is_wildcard_for("www.acme.co.uk"));
+    }
+}
@@ -0,0 +1,943 @@
+use actix::{Actor, Addr, Handler, Message, SyncArbiter, SyncContext};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{header, HeaderMap, Method, StatusCode};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use app_state::AppState;
+use bytes::Bytes;
+use futures::{stream, Async, Future, Poll, Stream};
+use glob::Glob;
+use har::NameValue;
+use proxy_response::UpstreamResponse;
+use proxy_transform::upstream_url;
+use serde_json;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///
+/// Added to responses for cacheable paths, with
+/// a value of either `hit` or `miss`
+///
+pub const CACHE_HEADER: &str = "x-bs-cache";
+
+///
+/// An opt-in cache of static assets from the target, kept on
+/// disk so that it survives restarts.
+///
+/// Only `GET` requests for the given paths are cached - everything
+/// else (including every page) always goes to the target
+///
+/// # Examples
+///
+/// ```yaml
+/// cache:
+///   enabled: true
+///   paths: ["/static/*", "/media/catalog/*"]
+///   max_size_mb: 1024
+/// ```
+///
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    ///
+    /// Relative to the working directory
+    ///
+    pub dir: String,
+    pub paths: Vec<Glob>,
+    ///
+    /// The oldest entries are removed once the cache grows past this
+    ///
+    pub max_size_mb: u64,
+    ///
+    /// Larger responses are passed through without being stored,
+    /// since each one is held in memory until it's complete
+    ///
+    pub max_entry_mb: u64,
+    ///
+    /// How long to keep responses that have no `Cache-Control` header
+    ///
+    pub default_max_age_secs: u64,
+    ///
+    /// When given, `Cache-Control` from the target is ignored
+    /// and every response is kept for this long instead
+    ///
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            enabled: false,
+            dir: ".config-gen-cache".to_string(),
+            paths: vec![Glob::new("/static/*").expect("valid glob")],
+            max_size_mb: 512,
+            max_entry_mb: 16,
+            default_max_age_secs: 86_400,
+            max_age_secs: None,
+        }
+    }
+}
+
+///
+/// How long a response may be cached for, according to its
+/// `Cache-Control` header. `None` means it must not be cached
+///
+/// # Examples
+///
+/// ```
+/// # extern crate actix_web;
+/// # extern crate bs;
+/// # use actix_web::http::HeaderMap;
+/// # use bs::disk_cache::max_age;
+/// let mut headers = HeaderMap::new();
+/// assert_eq!(max_age(&headers, 60), Some(60));
+///
+/// headers.insert("cache-control", "public, max-age=31536000".parse().unwrap());
+/// assert_eq!(max_age(&headers, 60), Some(31536000));
+///
+/// headers.insert("cache-control", "no-store".parse().unwrap());
+/// assert_eq!(max_age(&headers, 60), None);
+/// ```
+///
+pub fn max_age(headers: &HeaderMap, default_secs: u64) -> Option<u64> {
+    let directives = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_lowercase())
+        .collect::<Vec<String>>();
+
+    let forbidden = ["no-store", "no-cache", "private"];
+    if directives.iter().any(|d| forbidden.contains(&d.as_str())) {
+        return None;
+    }
+
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .filter_map(|d| {
+                let mut parts = d.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(n), Some(value)) if n.trim() == name => value.trim().parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    };
+
+    match seconds("s-maxage").or_else(|| seconds("max-age")) {
+        Some(0) => None,
+        Some(secs) => Some(secs),
+        None => Some(default_secs),
+    }
+}
+
+///
+/// Where a single request is (or would be) stored in the cache
+///
+#[derive(Clone)]
+pub struct CacheKey {
+    store: CacheStore,
+    name: String,
+    url: String,
+    path: String,
+    default_max_age_secs: u64,
+    max_age_secs: Option<u64>,
+}
+
+impl CacheKey {
+    ///
+    /// `None` when the cache is disabled, or when the
+    /// request isn't one that can be cached
+    ///
+    pub fn for_request(req: &HttpRequest<AppState>) -> Option<CacheKey> {
+        let config = &req.state().program_config.cache;
+        let store = req.state().cache.clone()?;
+
+        let cacheable = *req.method() == Method::GET
+            && !req.headers().contains_key(header::RANGE)
+            && config.paths.iter().any(|glob| glob.matches(req.path()));
+
+        if !cacheable {
+            return None;
+        }
+
+        // The body is stored exactly as it was sent, so clients
+        // that accept different encodings get different entries
+        let url = upstream_url(req);
+        let encoding = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+
+        Some(CacheKey {
+            store,
+            name: entry_name(&url, encoding),
+            url,
+            path: req.path().to_string(),
+            default_max_age_secs: config.default_max_age_secs,
+            max_age_secs: config.max_age_secs,
+        })
+    }
+
+    ///
+    /// Checked in memory, so requests for anything that's
+    /// not in the cache never wait on the disk
+    ///
+    pub fn is_stored(&self) -> bool {
+        self.store.contains(&self.name)
+    }
+
+    ///
+    /// The stored response, if there's one that hasn't expired.
+    ///
+    /// It's read on the cache's own threads, not the worker's
+    ///
+    pub fn lookup(&self) -> Box<Future<Item = Option<UpstreamResponse>, Error = Error>> {
+        let url = self.url.clone();
+        let read = {
+            let index = self
+                .store
+                .index
+                .lock()
+                .expect("should lock & unwrap cache index");
+            let version = index
+                .entries
+                .get(&self.name)
+                .map_or(0, |entry| entry.version);
+            self.store.io.send(ReadEntry {
+                name: self.name.clone(),
+                version,
+            })
+        };
+
+        Box::new(
+            read.then(move |res| -> Result<Option<UpstreamResponse>, Error> {
+                let (meta, body) = match res {
+                    Ok(Some(entry)) => entry,
+                    _ => return Ok(None),
+                };
+
+                // guards against two URLs sharing a name
+                if meta.url != url {
+                    return Ok(None);
+                }
+
+                let mut headers = HeaderMap::new();
+                for h in meta.headers.iter() {
+                    let name = HeaderName::from_bytes(h.name.as_bytes());
+                    let value = HeaderValue::from_str(&h.value);
+                    if let (Ok(name), Ok(value)) = (name, value) {
+                        headers.append(name, value);
+                    }
+                }
+                headers.insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+
+                Ok(StatusCode::from_u16(meta.status)
+                    .ok()
+                    .map(|status| UpstreamResponse {
+                        status,
+                        headers,
+                        body: Box::new(stream::once(Ok(Bytes::from(body)))),
+                    }))
+            }),
+        )
+    }
+
+    ///
+    /// Store a response from the target as its body streams through,
+    /// if the status & headers allow it to be cached.
+    ///
+    /// Responses that set cookies are never stored
+    ///
+    pub fn store(self, upstream: UpstreamResponse) -> UpstreamResponse {
+        let max_age = match self.max_age_secs {
+            Some(secs) => Some(secs),
+            None => max_age(&upstream.headers, self.default_max_age_secs),
+        };
+
+        let cacheable =
+            upstream.status == StatusCode::OK && !upstream.headers.contains_key(header::SET_COOKIE);
+
+        let max_age = match (cacheable, max_age) {
+            (true, Some(secs)) if secs > 0 => secs,
+            _ => return upstream,
+        };
+
+        // The body is served in one go from the cache
+        let headers = upstream
+            .headers
+            .iter()
+            .filter(|(name, _)| {
+                **name != header::CONTENT_LENGTH && **name != header::TRANSFER_ENCODING
+            })
+            .filter_map(|(name, value)| {
+                value.to_str().ok().map(|value| NameValue {
+                    name: name.as_str().to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .collect();
+
+        let stored_at = now_secs();
+        let meta = CachedMeta {
+            url: self.url,
+            path: self.path,
+            status: upstream.status.as_u16(),
+            headers,
+            stored_at,
+            expires_at: stored_at + max_age,
+            size: 0,
+        };
+
+        let mut resp_headers = upstream.headers;
+        resp_headers.insert(CACHE_HEADER, HeaderValue::from_static("miss"));
+
+        UpstreamResponse {
+            status: upstream.status,
+            headers: resp_headers,
+            body: Box::new(CacheBody {
+                stream: upstream.body,
+                buffer: Some(vec![]),
+                pending: Some((self.store, self.name, meta)),
+            }),
+        }
+    }
+}
+
+///
+/// Details of a stored response, kept alongside its body
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedMeta {
+    url: String,
+    path: String,
+    status: u16,
+    headers: Vec<NameValue>,
+    stored_at: u64,
+    expires_at: u64,
+    size: u64,
+}
+
+///
+/// The cache, as shared by every worker.
+///
+/// What's stored (and how big it is) is tracked in memory, so that
+/// lookups, eviction & invalidation never have to list the directory.
+///
+/// The files themselves are only ever touched on the cache's own thread.
+/// There's just the one, and messages for it are sent while the index is
+/// locked, so a write & a removal of the same entry always happen on disk
+/// in the same order as they did in memory
+///
+#[derive(Clone)]
+pub struct CacheStore {
+    max_bytes: u64,
+    max_entry_bytes: u64,
+    index: Arc<Mutex<CacheIndex>>,
+    io: Addr<CacheIo>,
+}
+
+impl CacheStore {
+    ///
+    /// Picks up whatever was stored by a previous run - this
+    /// reads the directory, so only happens once at startup
+    ///
+    pub fn open(config: &CacheConfig) -> CacheStore {
+        let files = CacheFiles {
+            dir: PathBuf::from(&config.dir),
+        };
+        let index = Arc::new(Mutex::new(files.scan()));
+
+        let io_index = index.clone();
+        let io = SyncArbiter::start(1, move || CacheIo {
+            files: files.clone(),
+            index: io_index.clone(),
+        });
+
+        CacheStore {
+            max_bytes: config.max_size_mb * 1024 * 1024,
+            max_entry_bytes: config.max_entry_mb.min(config.max_size_mb) * 1024 * 1024,
+            index,
+            io,
+        }
+    }
+    fn contains(&self, name: &str) -> bool {
+        let mut index = self.index.lock().expect("should lock & unwrap cache index");
+        match index.entries.get(name).map(|entry| entry.expires_at) {
+            Some(expires_at) if expires_at > now_secs() => true,
+            Some(..) => {
+                index.remove(name);
+                self.io.do_send(RemoveEntries(vec![name.to_string()]));
+                false
+            }
+            None => false,
+        }
+    }
+    fn write(&self, name: String, meta: CachedMeta, body: Vec<u8>) {
+        let mut index = self.index.lock().expect("should lock & unwrap cache index");
+        let version = index.insert(name.clone(), IndexedEntry::from(&meta));
+        let evicted = index.evict(self.max_bytes);
+        self.io.do_send(WriteEntry {
+            name,
+            version,
+            meta,
+            body,
+        });
+        if !evicted.is_empty() {
+            self.io.do_send(RemoveEntries(evicted));
+        }
+    }
+
+    ///
+    /// Remove every entry, or only those with a path that matches
+    ///
+    pub fn invalidate(&self, path: Option<&Glob>) -> usize {
+        let mut index = self.index.lock().expect("should lock & unwrap cache index");
+        let removed = index.remove_matching(path);
+        let count = removed.len();
+        if count > 0 {
+            self.io.do_send(RemoveEntries(removed));
+        }
+        count
+    }
+}
+
+///
+/// An entry as it's tracked in memory
+///
+#[derive(Debug, Clone, PartialEq)]
+struct IndexedEntry {
+    path: String,
+    size: u64,
+    stored_at: u64,
+    expires_at: u64,
+    ///
+    /// Tells this entry apart from an earlier one with the same name
+    ///
+    version: u64,
+}
+
+impl<'a> From<&'a CachedMeta> for IndexedEntry {
+    fn from(meta: &CachedMeta) -> IndexedEntry {
+        IndexedEntry {
+            path: meta.path.clone(),
+            size: meta.size,
+            stored_at: meta.stored_at,
+            expires_at: meta.expires_at,
+            version: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<String, IndexedEntry>,
+    total: u64,
+    versions: u64,
+}
+
+impl CacheIndex {
+    fn insert(&mut self, name: String, mut entry: IndexedEntry) -> u64 {
+        self.versions += 1;
+        entry.version = self.versions;
+        self.total += entry.size;
+        if let Some(previous) = self.entries.insert(name, entry) {
+            self.total -= previous.size;
+        }
+        self.versions
+    }
+    fn remove(&mut self, name: &str) {
+        if let Some(previous) = self.entries.remove(name) {
+            self.total -= previous.size;
+        }
+    }
+
+    ///
+    /// Drop the oldest entries until the cache fits within
+    /// its limit, giving back the ones that were dropped
+    ///
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        if self.total <= max_bytes {
+            return vec![];
+        }
+        let mut oldest = self
+            .entries
+            .iter()
+            .map(|(name, entry)| (entry.stored_at, name.clone()))
+            .collect::<Vec<(u64, String)>>();
+        oldest.sort();
+
+        let mut evicted = vec![];
+        for (_, name) in oldest {
+            if self.total <= max_bytes {
+                break;
+            }
+            debug!("evicting {} from the cache", name);
+            self.remove(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+    fn remove_matching(&mut self, path: Option<&Glob>) -> Vec<String> {
+        let names = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| path.map_or(true, |glob| glob.matches(&entry.path)))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<String>>();
+        for name in names.iter() {
+            self.remove(name);
+        }
+        names
+    }
+}
+
+///
+/// Makes every temporary file name unique, along with the process id
+///
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Each entry is a pair of files in the cache directory,
+/// `<name>.json` for the status & headers and `<name>.body`
+///
+#[derive(Debug, Clone)]
+struct CacheFiles {
+    dir: PathBuf,
+}
+
+impl CacheFiles {
+    fn meta_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+    fn body_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", name))
+    }
+    fn temp_path(&self, name: &str) -> PathBuf {
+        let count = TEMP_FILES.fetch_add(1, Ordering::SeqCst);
+        self.dir
+            .join(format!("{}.{}-{}.tmp", name, process::id(), count))
+    }
+    fn read(&self, name: &str) -> Option<(CachedMeta, Vec<u8>)> {
+        let meta = fs::read(self.meta_path(name)).ok()?;
+        let meta: CachedMeta = serde_json::from_slice(&meta).ok()?;
+        if meta.expires_at <= now_secs() {
+            self.remove(name);
+            return None;
+        }
+        let body = fs::read(self.body_path(name)).ok()?;
+        Some((meta, body))
+    }
+
+    ///
+    /// Both files are written elsewhere & then moved into place, so that
+    /// concurrent writes of the same entry can't interleave. The body
+    /// goes first, so a metadata file always has a complete body
+    ///
+    fn write(&self, name: &str, meta: &CachedMeta, body: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        self.write_file(name, body, self.body_path(name))?;
+        self.write_file(name, &serde_json::to_vec(meta)?, self.meta_path(name))
+    }
+    fn write_file(&self, name: &str, content: &[u8], path: PathBuf) -> io::Result<()> {
+        let temp = self.temp_path(name);
+        fs::write(&temp, content)
+            .and_then(|()| fs::rename(&temp, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&temp);
+                e
+            })
+    }
+    fn remove(&self, name: &str) {
+        let _ = fs::remove_file(self.meta_path(name));
+        let _ = fs::remove_file(self.body_path(name));
+    }
+
+    ///
+    /// Index what's in the directory, clearing up expired
+    /// entries & anything left over from an unfinished write
+    ///
+    fn scan(&self) -> CacheIndex {
+        let mut index = CacheIndex::default();
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(_) => return index,
+        };
+        let now = now_secs();
+        for path in dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                }
+                Some("json") => {
+                    let meta = fs::read(&path)
+                        .ok()
+                        .and_then(|meta| serde_json::from_slice::<CachedMeta>(&meta).ok());
+                    match meta {
+                        Some(ref meta) if meta.expires_at > now => {
+                            index.insert(name, IndexedEntry::from(meta));
+                        }
+                        _ => self.remove(&name),
+                    }
+                }
+                _ => {}
+            }
+        }
+        index
+    }
+}
+
+///
+/// Does the blocking work for a [CacheStore], on its own thread
+///
+struct CacheIo {
+    files: CacheFiles,
+    index: Arc<Mutex<CacheIndex>>,
+}
+
+impl Actor for CacheIo {
+    type Context = SyncContext<Self>;
+}
+
+struct ReadEntry {
+    name: String,
+    version: u64,
+}
+
+impl Message for ReadEntry {
+    type Result = Option<(CachedMeta, Vec<u8>)>;
+}
+
+struct WriteEntry {
+    name: String,
+    version: u64,
+    meta: CachedMeta,
+    body: Vec<u8>,
+}
+
+impl Message for WriteEntry {
+    type Result = ();
+}
+
+struct RemoveEntries(Vec<String>);
+
+impl Message for RemoveEntries {
+    type Result = ();
+}
+
+impl CacheIo {
+    ///
+    /// Drop an entry that couldn't be read or written - unless it
+    /// has since been stored again, which leaves the newer one alone
+    ///
+    fn forget(&self, name: &str, version: u64) {
+        let mut index = self.index.lock().expect("should lock & unwrap cache index");
+        if index.entries.get(name).map(|entry| entry.version) == Some(version) {
+            index.remove(name);
+        }
+    }
+}
+
+impl Handler<ReadEntry> for CacheIo {
+    type Result = Option<(CachedMeta, Vec<u8>)>;
+
+    fn handle(&mut self, msg: ReadEntry, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let entry = self.files.read(&msg.name);
+        if entry.is_none() {
+            self.forget(&msg.name, msg.version);
+        }
+        entry
+    }
+}
+
+impl Handler<WriteEntry> for CacheIo {
+    type Result = ();
+
+    fn handle(&mut self, msg: WriteEntry, _ctx: &mut SyncContext<Self>) {
+        match self.files.write(&msg.name, &msg.meta, &msg.body) {
+            Ok(()) => debug!("cached {}", msg.meta.url),
+            Err(e) => {
+                error!("could not cache {}, {}", msg.meta.url, e);
+                self.forget(&msg.name, msg.version);
+            }
+        }
+    }
+}
+
+impl Handler<RemoveEntries> for CacheIo {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveEntries, _ctx: &mut SyncContext<Self>) {
+        for name in msg.0.iter() {
+            self.files.remove(name);
+        }
+    }
+}
+
+///
+/// Passes a body through untouched, writing it to the cache
+/// once it has been received in full
+///
+struct CacheBody<S> {
+    stream: S,
+    ///
+    /// Dropped when the body is too big to store, or fails
+    ///
+    buffer: Option<Vec<u8>>,
+    pending: Option<(CacheStore, String, CachedMeta)>,
+}
+
+impl<S> Stream for CacheBody<S>
+where
+    S: Stream<Item = Bytes, Error = PayloadError>,
+{
+    type Item = Bytes;
+    type Error = PayloadError;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, PayloadError> {
+        let next = match self.stream.poll() {
+            Ok(next) => next,
+            Err(e) => {
+                self.buffer = None;
+                return Err(e);
+            }
+        };
+        match next {
+            Async::Ready(Some(ref chunk)) => {
+                let max_bytes = self
+                    .pending
+                    .as_ref()
+                    .map_or(0, |(store, _, _)| store.max_entry_bytes as usize);
+                let too_big = match self.buffer {
+                    Some(ref mut buffer) => {
+                        buffer.extend_from_slice(chunk);
+                        buffer.len() > max_bytes
+                    }
+                    None => false,
+                };
+                if too_big {
+                    self.buffer = None;
+                }
+            }
+            Async::Ready(None) => self.finish(),
+            Async::NotReady => {}
+        }
+        Ok(next)
+    }
+}
+
+impl<S> CacheBody<S> {
+    fn finish(&mut self) {
+        if let (Some(body), Some((store, name, mut meta))) =
+            (self.buffer.take(), self.pending.take())
+        {
+            meta.size = body.len() as u64;
+            store.write(name, meta, body);
+        }
+    }
+}
+
+///
+/// Remove entries from the cache - every one of them, or
+/// only those with a path matching `?path=<glob>`
+///
+pub fn handle_cache_invalidate(req: &HttpRequest<AppState>) -> HttpResponse {
+    let glob = match req.query().get("path") {
+        Some(path) => match Glob::new(path) {
+            Ok(glob) => Some(glob),
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+        None => None,
+    };
+
+    let removed = match req.state().cache {
+        Some(ref store) => store.invalidate(glob.as_ref()),
+        None => 0,
+    };
+
+    HttpResponse::Ok().json(json!({ "removed": removed }))
+}
+
+fn entry_name(url: &str, encoding: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    encoding.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::System;
+    use tempdir::TempDir;
+
+    fn meta(url: &str, path: &str, stored_at: u64, size: u64) -> CachedMeta {
+        CachedMeta {
+            url: url.into(),
+            path: path.into(),
+            status: 200,
+            headers: vec![],
+            stored_at,
+            expires_at: now_secs() + 60,
+            size,
+        }
+    }
+
+    fn index(entries: Vec<(&str, CachedMeta)>) -> CacheIndex {
+        let mut index = CacheIndex::default();
+        for (name, meta) in entries {
+            index.insert(name.to_string(), IndexedEntry::from(&meta));
+        }
+        index
+    }
+
+    #[test]
+    fn test_max_age_precedence() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            "max-age=60, s-maxage=600".parse().unwrap(),
+        );
+        assert_eq!(max_age(&headers, 1), Some(600));
+
+        headers.insert(header::CACHE_CONTROL, "max-age=0".parse().unwrap());
+        assert_eq!(max_age(&headers, 1), None);
+
+        headers.insert(
+            header::CACHE_CONTROL,
+            "Private, max-age=60".parse().unwrap(),
+        );
+        assert_eq!(max_age(&headers, 1), None);
+    }
+
+    #[test]
+    fn test_store_evicts_oldest() {
+        let mut index = index(vec![
+            ("a", meta("https://www.acme.com/a.js", "/a.js", 1, 6)),
+            ("b", meta("https://www.acme.com/b.js", "/b.js", 2, 6)),
+        ]);
+        assert_eq!(index.evict(10), vec!["a".to_string()]);
+        assert_eq!(index.total, 6);
+        assert!(index.entries.contains_key("b"));
+        assert!(index.evict(10).is_empty());
+
+        // storing an entry again replaces its size
+        index.insert(
+            "b".into(),
+            IndexedEntry::from(&meta("https://www.acme.com/b.js", "/b.js", 3, 2)),
+        );
+        assert_eq!(index.total, 2);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut index = index(vec![
+            (
+                "a",
+                meta("https://www.acme.com/static/a.js", "/static/a.js", 1, 1),
+            ),
+            (
+                "b",
+                meta("https://www.acme.com/media/b.png", "/media/b.png", 2, 1),
+            ),
+        ]);
+
+        let glob = Glob::new("/static/*").unwrap();
+        assert_eq!(index.remove_matching(Some(&glob)), vec!["a".to_string()]);
+        assert!(index.entries.contains_key("b"));
+
+        assert_eq!(index.remove_matching(None), vec!["b".to_string()]);
+        assert_eq!(index.total, 0);
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new("cache").unwrap();
+        let files = CacheFiles {
+            dir: dir.path().to_path_buf(),
+        };
+
+        let a = meta("https://www.acme.com/a.js", "/a.js", 1, 1);
+        files.write("a", &a, b"a").unwrap();
+        files.write("a", &a, b"a").unwrap();
+        assert_eq!(files.read("a").unwrap().1, b"a");
+
+        // leftovers from a write that never finished
+        fs::write(dir.path().join("b.123-0.tmp"), b"b").unwrap();
+
+        let index = files.scan();
+        assert_eq!(index.entries.len(), 1);
+        assert_eq!(index.total, 1);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_expired_entries_are_removed() {
+        let dir = TempDir::new("cache").unwrap();
+        let files = CacheFiles {
+            dir: dir.path().to_path_buf(),
+        };
+        let expired = CachedMeta {
+            expires_at: 1,
+            ..meta("https://www.acme.com/a.js", "/a.js", 1, 1)
+        };
+        files.write("a", &expired, b"a").unwrap();
+        files.write("b", &expired, b"b").unwrap();
+
+        assert!(files.read("a").is_none());
+        assert!(files.scan().entries.is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_invalidate_racing_writes() {
+        let dir = TempDir::new("cache").unwrap();
+        let config = CacheConfig {
+            dir: dir.path().to_string_lossy().to_string(),
+            ..CacheConfig::default()
+        };
+        let mut sys = System::new("cache");
+        let store = CacheStore::open(&config);
+
+        for _ in 0..20 {
+            store.write(
+                "a".into(),
+                meta("https://www.acme.com/a.js", "/a.js", 1, 1),
+                b"a".to_vec(),
+            );
+            assert_eq!(store.invalidate(None), 1);
+        }
+
+        // handled after everything that was sent before it
+        let read = store.io.send(ReadEntry {
+            name: "a".into(),
+            version: 0,
+        });
+        assert!(sys.block_on(read).unwrap().is_none());
+        assert_eq!(store.index.lock().unwrap().total, 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
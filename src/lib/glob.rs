@@ -0,0 +1,89 @@
+use regex;
+use regex::Regex;
+use serde::de::{self, Deserialize, Deserializer};
+use std::fmt;
+
+///
+/// A simple glob pattern, as used for matching paths
+/// & content types from the config file.
+///
+/// `*` matches any number of characters (including `/`)
+/// and `?` matches exactly one
+///
+/// # Examples
+///
+/// ```
+/// use bs::glob::Glob;
+///
+/// let glob = Glob::new("/static/*/requirejs-config.js").unwrap();
+/// assert!(glob.matches("/static/version1/frontend/Acme/default/en_GB/requirejs-config.js"));
+/// assert!(!glob.matches("/media/requirejs-config.json"));
+/// ```
+///
+#[derive(Clone)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, regex::Error> {
+        let mut matcher = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => matcher.push_str(".*"),
+                '?' => matcher.push('.'),
+                c => matcher.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        matcher.push('$');
+        Regex::new(&matcher).map(|regex| Glob {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+    pub fn matches(&self, input: &str) -> bool {
+        self.regex.is_match(input)
+    }
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Glob({:?})", self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for Glob {
+    fn deserialize<D>(deserializer: D) -> Result<Glob, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Glob::new(&pattern).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        let glob = Glob::new("*requirejs-config.js*").unwrap();
+        assert!(glob.matches("/static/frontend/requirejs-config.js"));
+        assert!(glob.matches("/requirejs-config.js?v=1"));
+        assert!(!glob.matches("/static/frontend/require.js"));
+
+        let glob = Glob::new("text/*").unwrap();
+        assert!(glob.matches("text/css"));
+        assert!(!glob.matches("application/json"));
+
+        // regex characters are treated literally
+        let glob = Glob::new("/a.b?").unwrap();
+        assert!(glob.matches("/a.bc"));
+        assert!(!glob.matches("/axbc"));
+    }
+}
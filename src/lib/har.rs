@@ -0,0 +1,836 @@
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use app_state::AppState;
+use base64;
+use bytes::Bytes;
+use content_encoding::{decode_body, ContentCoding};
+use futures::{Async, Poll, Stream};
+use serde_json;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use upstream_error::UpstreamFailure;
+use url::form_urlencoded;
+
+///
+/// How many entries are kept in memory for `/__bs/har`, the
+/// file given with `--record-har` has every one of them
+///
+const MAX_HAR_ENTRIES: usize = 1000;
+
+///
+/// How every archive ends, entries are written just before it
+///
+const HAR_FOOTER: &[u8] = b"]}}";
+
+///
+/// Bodies are cut short past this size
+///
+const MAX_BODY_BYTES: usize = 5_000_000;
+
+///
+/// An HTTP Archive, as understood by browser devtools.
+///
+/// See http://www.softwareishard.com/blog/har-12-spec/
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HarLog {
+    pub version: &'static str,
+    pub creator: HarCreator,
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HarCreator {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+impl Har {
+    pub fn new(entries: Vec<HarEntry>) -> Har {
+        Har {
+            log: HarLog {
+                version: "1.2",
+                creator: HarCreator {
+                    name: "config-gen",
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    pub cache: HarCache,
+    pub timings: HarTimings,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub headers: Vec<NameValue>,
+    pub query_string: Vec<NameValue>,
+    pub cookies: Vec<NameValue>,
+    pub headers_size: i64,
+    pub body_size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    ///
+    /// Where the request was forwarded to
+    ///
+    #[serde(rename = "_upstreamUrl")]
+    pub upstream_url: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    pub mime_type: String,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub headers: Vec<NameValue>,
+    pub cookies: Vec<NameValue>,
+    ///
+    /// The body as it was sent to the browser, after any rewrites
+    ///
+    pub content: HarContent,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+    ///
+    /// The response as the target sent it, before any rewrites
+    ///
+    #[serde(rename = "_upstream", skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<HarUpstreamResponse>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HarUpstreamResponse {
+    pub status: u16,
+    pub headers: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<HarContent>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HarCache {}
+
+///
+/// All in milliseconds, `-1` means not applicable
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct HarTimings {
+    pub blocked: f64,
+    pub dns: f64,
+    pub connect: f64,
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+    pub ssl: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+///
+/// The details of a single request, collected as it
+/// makes its way through the proxy
+///
+#[derive(Clone)]
+pub struct HarCapture {
+    req: HttpRequest<AppState>,
+    upstream_url: String,
+    started: Instant,
+    started_time: SystemTime,
+    request_body: Option<Bytes>,
+}
+
+impl HarCapture {
+    pub fn new(req: &HttpRequest<AppState>, upstream_url: String) -> HarCapture {
+        HarCapture {
+            req: req.clone(),
+            upstream_url,
+            started: Instant::now(),
+            started_time: SystemTime::now(),
+            request_body: None,
+        }
+    }
+    pub fn set_request_body(&mut self, body: Bytes) {
+        self.request_body = Some(body);
+    }
+    fn bodies(&self) -> bool {
+        self.req.state().opts.har.bodies
+    }
+
+    ///
+    /// Start copying the target's body, so that it can
+    /// be compared with what the browser receives
+    ///
+    pub fn tap_upstream<S>(&self, stream: S) -> (HarBody<S>, Option<BodyBuffer>) {
+        let buffer = match self.bodies() {
+            true => Some(BodyBuffer::default()),
+            false => None,
+        };
+        let tap = HarBody {
+            stream,
+            buffer: buffer.clone(),
+            entry: None,
+        };
+        (tap, buffer)
+    }
+
+    ///
+    /// Wrap the body sent to the browser, the entry is
+    /// recorded once it's finished (or dropped).
+    ///
+    /// `upstream_body` is only given when the body was rewritten
+    ///
+    pub fn tap_response<S>(
+        self,
+        stream: S,
+        upstream_status: StatusCode,
+        upstream_headers: &HeaderMap,
+        upstream_body: Option<BodyBuffer>,
+        response: &HttpResponse,
+    ) -> HarBody<S> {
+        let buffer = match self.bodies() {
+            true => Some(BodyBuffer::default()),
+            false => None,
+        };
+        let wait = self.started.elapsed();
+        HarBody {
+            stream,
+            buffer: buffer.clone(),
+            entry: Some(PendingEntry {
+                upstream_status,
+                upstream_headers: upstream_headers.clone(),
+                upstream_body,
+                status: response.status(),
+                headers: response.headers().clone(),
+                wait,
+                capture: self,
+            }),
+        }
+    }
+}
+
+///
+/// A copy of a body, as it streams through
+///
+#[derive(Clone, Default)]
+pub struct BodyBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl BodyBuffer {
+    fn push(&self, chunk: &[u8]) {
+        let mut buffer = self.0.borrow_mut();
+        let space = MAX_BODY_BYTES.saturating_sub(buffer.len());
+        buffer.extend_from_slice(&chunk[..chunk.len().min(space)]);
+    }
+    fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+struct PendingEntry {
+    capture: HarCapture,
+    upstream_status: StatusCode,
+    upstream_headers: HeaderMap,
+    upstream_body: Option<BodyBuffer>,
+    status: StatusCode,
+    headers: HeaderMap,
+    wait: Duration,
+}
+
+///
+/// Passes a body through untouched, keeping a copy
+/// of it when bodies are being recorded
+///
+pub struct HarBody<S> {
+    stream: S,
+    buffer: Option<BodyBuffer>,
+    entry: Option<PendingEntry>,
+}
+
+impl<S> Stream for HarBody<S>
+where
+    S: Stream<Item = Bytes>,
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        let next = self.stream.poll()?;
+        match next {
+            Async::Ready(Some(ref chunk)) => {
+                if let Some(ref buffer) = self.buffer {
+                    buffer.push(chunk);
+                }
+            }
+            Async::Ready(None) => self.finish(),
+            Async::NotReady => {}
+        }
+        Ok(next)
+    }
+}
+
+impl<S> HarBody<S> {
+    fn finish(&mut self) {
+        if let Some(pending) = self.entry.take() {
+            let body = self.buffer.as_ref().map(|b| b.bytes());
+            let req = pending.capture.req.clone();
+            record(&req, pending_entry(pending, body));
+        }
+    }
+}
+
+///
+/// Bodies that are cut short (by the browser going
+/// away, for example) are still recorded
+///
+impl<S> Drop for HarBody<S> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn pending_entry(pending: PendingEntry, body: Option<Vec<u8>>) -> HarEntry {
+    let total = pending.capture.started.elapsed();
+    let wait = millis(pending.wait);
+    let upstream_headers = &pending.upstream_headers;
+    let upstream = HarUpstreamResponse {
+        status: pending.upstream_status.as_u16(),
+        headers: name_values(upstream_headers),
+        content: pending
+            .upstream_body
+            .map(|b| upstream_har_content(upstream_headers, b.bytes())),
+    };
+    let content = har_content(&pending.headers, body);
+    let mut entry = har_entry(
+        &pending.capture,
+        pending.status,
+        &pending.headers,
+        content,
+        wait,
+        millis(total) - wait,
+    );
+    entry.response.upstream = Some(upstream);
+    entry
+}
+
+///
+/// Record a request that failed before the target responded
+///
+pub fn record_failure(
+    req: &HttpRequest<AppState>,
+    failure: &UpstreamFailure,
+    response: &HttpResponse,
+) {
+    let capture = HarCapture {
+        req: req.clone(),
+        upstream_url: failure.url.clone(),
+        started: Instant::now(),
+        started_time: UNIX_EPOCH + Duration::from_secs(failure.time),
+        request_body: None,
+    };
+    let mut content = har_content(response.headers(), None);
+    content.comment = Some(format!("{:?}: {}", failure.kind, failure.reason));
+    let entry = har_entry(
+        &capture,
+        response.status(),
+        response.headers(),
+        content,
+        failure.elapsed_ms as f64,
+        0.0,
+    );
+    record(req, entry);
+}
+
+fn har_entry(
+    capture: &HarCapture,
+    status: StatusCode,
+    headers: &HeaderMap,
+    content: HarContent,
+    wait: f64,
+    receive: f64,
+) -> HarEntry {
+    let req = &capture.req;
+    let info = req.connection_info();
+    let bodies = capture.bodies();
+
+    HarEntry {
+        started_date_time: iso_8601(capture.started_time),
+        time: wait + receive,
+        request: HarRequest {
+            method: req.method().to_string(),
+            url: format!("{}://{}{}", info.scheme(), info.host(), req.uri()),
+            http_version: format!("{:?}", req.version()),
+            headers: name_values(req.headers()),
+            query_string: form_urlencoded::parse(req.query_string().as_bytes())
+                .map(|(name, value)| NameValue {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            cookies: vec![],
+            headers_size: -1,
+            body_size: capture
+                .request_body
+                .as_ref()
+                .map_or(0, |body| body.len() as i64),
+            post_data: match (bodies, &capture.request_body) {
+                (true, &Some(ref body)) => Some(HarPostData {
+                    mime_type: header_str(req.headers(), header::CONTENT_TYPE),
+                    text: String::from_utf8_lossy(body).to_string(),
+                }),
+                _ => None,
+            },
+            upstream_url: capture.upstream_url.clone(),
+        },
+        response: HarResponse {
+            status: status.as_u16(),
+            status_text: status.canonical_reason().unwrap_or("").to_string(),
+            http_version: "HTTP/1.1".to_string(),
+            headers: name_values(headers),
+            cookies: vec![],
+            redirect_url: header_str(headers, header::LOCATION),
+            headers_size: -1,
+            body_size: content.size,
+            content,
+            upstream: None,
+        },
+        cache: HarCache {},
+        timings: HarTimings {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: -1.0,
+            send: 0.0,
+            wait,
+            receive,
+            ssl: -1.0,
+        },
+    }
+}
+
+///
+/// Bodies are decoded, as the HAR format expects, then given
+/// as text when possible - binary bodies are base64 encoded
+///
+fn har_content(headers: &HeaderMap, body: Option<Vec<u8>>) -> HarContent {
+    let mut content = empty_content(headers);
+
+    if let Some(body) = body {
+        let truncated = body.len() >= MAX_BODY_BYTES;
+        let decoded = match ContentCoding::from_headers(headers) {
+            Some(ContentCoding::Identity) => Ok(body),
+            Some(coding) if !truncated => {
+                decode_body(coding, &body).map_err(|e| (body, e.to_string()))
+            }
+            Some(..) => Err((body, "it was truncated".to_string())),
+            None => Err((body, "the content-encoding isn't supported".to_string())),
+        };
+        match decoded {
+            Ok(body) => set_text(&mut content, body),
+            Err((body, reason)) => {
+                set_base64(&mut content, &body);
+                content.comment = Some(format!("could not be decoded, {}", reason));
+            }
+        }
+        if truncated {
+            content.comment = Some(format!("truncated to {} bytes", MAX_BODY_BYTES));
+        }
+    }
+
+    content
+}
+
+///
+/// The body exactly as the target sent it, still compressed,
+/// so that a recording can be replayed byte for byte
+///
+fn upstream_har_content(headers: &HeaderMap, body: Vec<u8>) -> HarContent {
+    let mut content = empty_content(headers);
+    let is_encoded = ContentCoding::from_headers(headers) != Some(ContentCoding::Identity);
+
+    if body.len() >= MAX_BODY_BYTES {
+        content.comment = Some(format!("truncated to {} bytes", MAX_BODY_BYTES));
+    }
+    match is_encoded {
+        true => set_base64(&mut content, &body),
+        false => set_text(&mut content, body),
+    }
+
+    content
+}
+
+fn set_text(content: &mut HarContent, body: Vec<u8>) {
+    content.size = body.len() as i64;
+    match String::from_utf8(body) {
+        Ok(text) => content.text = Some(text),
+        Err(e) => set_base64(content, e.as_bytes()),
+    }
+}
+
+fn set_base64(content: &mut HarContent, body: &[u8]) {
+    content.size = body.len() as i64;
+    content.text = Some(base64::encode(body));
+    content.encoding = Some("base64");
+}
+
+fn empty_content(headers: &HeaderMap) -> HarContent {
+    HarContent {
+        size: headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(-1),
+        mime_type: header_str(headers, header::CONTENT_TYPE),
+        ..HarContent::default()
+    }
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).to_string(),
+        })
+        .collect()
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_micros()) / 1000.0
+}
+
+///
+/// The entries of the current session, shared by every worker.
+///
+/// The most recent ones are kept in memory, and with `--record-har`
+/// each one is also handed to a thread that appends it to the file
+///
+#[derive(Default)]
+pub struct HarRecorder {
+    entries: Mutex<VecDeque<HarEntry>>,
+    writer: Option<Mutex<Sender<HarEntry>>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+impl HarRecorder {
+    ///
+    /// Start a new archive in `file`, it's a valid (empty)
+    /// HAR file straight away and after every entry
+    ///
+    pub fn to_file(file: &str) -> io::Result<HarRecorder> {
+        let mut output = File::create(file)?;
+        let empty = serde_json::to_vec(&Har::new(vec![]))?;
+        debug_assert!(empty.ends_with(HAR_FOOTER));
+        output.write_all(&empty)?;
+
+        let (sender, receiver) = channel();
+        let name = file.to_string();
+        let writer_thread = thread::Builder::new()
+            .name("har-writer".into())
+            .spawn(move || write_entries(output, &name, receiver))?;
+
+        Ok(HarRecorder {
+            entries: Mutex::new(VecDeque::new()),
+            writer: Some(Mutex::new(sender)),
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    pub fn record(&self, entry: HarEntry) {
+        if let Some(ref writer) = self.writer {
+            let sent = writer
+                .lock()
+                .expect("should lock & unwrap har writer")
+                .send(entry.clone());
+            if sent.is_err() {
+                error!("the HAR file is no longer being written");
+            }
+        }
+
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("should lock & unwrap har entries");
+        if entries.len() >= MAX_HAR_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    ///
+    /// The entries that are still in memory, oldest first
+    ///
+    pub fn entries(&self) -> Vec<HarEntry> {
+        self.entries
+            .lock()
+            .expect("should lock & unwrap har entries")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+///
+/// Anything that's still queued is written before the
+/// program exits
+///
+impl Drop for HarRecorder {
+    fn drop(&mut self) {
+        self.writer.take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+///
+/// Runs until every sender has gone away. Each entry is written
+/// over the footer, which is then written again after it
+///
+fn write_entries(mut output: File, name: &str, entries: Receiver<HarEntry>) {
+    let mut first = true;
+    for entry in entries {
+        let written = serde_json::to_vec(&entry)
+            .map_err(io::Error::from)
+            .and_then(|json| append_entry(&mut output, &json, first));
+        match written {
+            Ok(()) => first = false,
+            Err(e) => error!("could not write to the HAR file `{}`, {}", name, e),
+        }
+    }
+}
+
+fn append_entry(output: &mut File, json: &[u8], first: bool) -> io::Result<()> {
+    output.seek(SeekFrom::End(-(HAR_FOOTER.len() as i64)))?;
+    if !first {
+        output.write_all(b",")?;
+    }
+    output.write_all(json)?;
+    output.write_all(HAR_FOOTER)?;
+    output.flush()
+}
+
+fn record(req: &HttpRequest<AppState>, entry: HarEntry) {
+    req.state().har.record(entry);
+}
+
+///
+/// Every entry in the current session, as a HAR file
+///
+pub fn handle_har(req: &HttpRequest<AppState>) -> HttpResponse {
+    let entries = req.state().har.entries();
+
+    match serde_json::to_string_pretty(&Har::new(entries)) {
+        Ok(t) => HttpResponse::Ok().content_type("application/json").body(t),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+///
+/// Format a time as UTC, in the form used by HAR files
+///
+/// # Examples
+///
+/// ```
+/// use bs::har::iso_8601;
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let time = UNIX_EPOCH + Duration::from_millis(1_539_788_400_123);
+/// assert_eq!(iso_8601(time), "2018-10-17T15:00:00.123Z");
+/// ```
+///
+pub fn iso_8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use content_encoding::Coder;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_har_content() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/html".parse().unwrap());
+        let content = har_content(&headers, Some(b"<p>hello</p>".to_vec()));
+        assert_eq!(content.text, Some("<p>hello</p>".to_string()));
+        assert_eq!(content.size, 12);
+        assert_eq!(content.encoding, None);
+
+        let mut encoder = Coder::encoder(ContentCoding::Gzip);
+        let mut gzipped = encoder.write(b"<p>hello</p>").unwrap();
+        gzipped.extend(encoder.finish().unwrap());
+
+        // devtools expects the decoded body
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let content = har_content(&headers, Some(gzipped.clone()));
+        assert_eq!(content.text, Some("<p>hello</p>".to_string()));
+        assert_eq!(content.size, 12);
+        assert_eq!(content.encoding, None);
+
+        // while replaying needs what the target sent
+        let content = upstream_har_content(&headers, gzipped.clone());
+        assert_eq!(content.text, Some(base64::encode(&gzipped)));
+        assert_eq!(content.encoding, Some("base64"));
+
+        let content = har_content(&headers, Some(b"hello".to_vec()));
+        assert_eq!(content.text, Some("aGVsbG8=".to_string()));
+        assert!(content.comment.unwrap().starts_with("could not be decoded"));
+
+        let content = har_content(&headers, None);
+        assert_eq!(content.text, None);
+        assert_eq!(content.size, -1);
+    }
+
+    #[test]
+    fn test_har_file_is_always_valid() {
+        let dir = TempDir::new("har").unwrap();
+        let file = dir.path().join("session.har");
+        let file = file.to_str().unwrap();
+        let recorder = HarRecorder::to_file(file).unwrap();
+
+        let read = || -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+        };
+        assert_eq!(read()["log"]["entries"], json!([]));
+
+        let entry = |status: u16| HarEntry {
+            started_date_time: iso_8601(UNIX_EPOCH),
+            time: 0.0,
+            request: HarRequest {
+                method: "GET".into(),
+                url: "http://127.0.0.1:8080/".into(),
+                http_version: "HTTP/1.1".into(),
+                headers: vec![],
+                query_string: vec![],
+                cookies: vec![],
+                headers_size: -1,
+                body_size: 0,
+                post_data: None,
+                upstream_url: "https://www.acme.com/".into(),
+            },
+            response: HarResponse {
+                status,
+                status_text: String::new(),
+                http_version: "HTTP/1.1".into(),
+                headers: vec![],
+                cookies: vec![],
+                content: HarContent::default(),
+                redirect_url: String::new(),
+                headers_size: -1,
+                body_size: -1,
+                upstream: None,
+            },
+            cache: HarCache {},
+            timings: HarTimings {
+                blocked: -1.0,
+                dns: -1.0,
+                connect: -1.0,
+                send: 0.0,
+                wait: 0.0,
+                receive: 0.0,
+                ssl: -1.0,
+            },
+        };
+
+        recorder.record(entry(200));
+        recorder.record(entry(404));
+        assert_eq!(recorder.entries().len(), 2);
+
+        // waits for the writer thread to finish
+        drop(recorder);
+        let entries = read()["log"]["entries"].clone();
+        assert_eq!(entries[0]["response"]["status"], 200);
+        assert_eq!(entries[1]["response"]["status"], 404);
+    }
+
+    #[test]
+    fn test_iso_8601_leap_year() {
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(iso_8601(time), "2000-02-29T00:00:00.000Z");
+    }
+}
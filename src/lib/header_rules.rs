@@ -0,0 +1,272 @@
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::HeaderMap;
+use actix_web::HttpRequest;
+use app_state::AppState;
+use glob::Glob;
+use serde::de::{self, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+///
+/// Headers to add, set or remove on the requests sent to
+/// the target, and on the responses sent back from it
+///
+/// # Examples
+///
+/// ```yaml
+/// headers:
+///   request:
+///     - set:
+///         X-Forwarded-Proto: "{scheme}"
+///         X-Forwarded-Host: "{host}"
+///       add:
+///         X-Forwarded-For: "{client_ip}"
+///   response:
+///     - remove: [Strict-Transport-Security]
+///     - paths: ["/checkout/*"]
+///       set:
+///         Cache-Control: no-cache
+/// ```
+///
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub request: Vec<HeaderRule>,
+    #[serde(default)]
+    pub response: Vec<HeaderRule>,
+}
+
+///
+/// Every rule that matches the path is applied, in order. Within
+/// a rule, headers are removed first, then set, then added.
+///
+/// Values can contain `{client_ip}`, `{scheme}` and `{host}`,
+/// which are replaced with details of the incoming request
+///
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HeaderRule {
+    ///
+    /// An empty list matches every path
+    ///
+    #[serde(default)]
+    pub paths: Vec<Glob>,
+
+    #[serde(default)]
+    pub remove: Vec<ValidHeaderName>,
+
+    ///
+    /// Replaces any existing values
+    ///
+    #[serde(default)]
+    pub set: HeaderList,
+
+    ///
+    /// Keeps any existing values
+    ///
+    #[serde(default)]
+    pub add: HeaderList,
+}
+
+impl HeaderRule {
+    pub fn matches(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|g| g.matches(path))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidHeaderName(pub HeaderName);
+
+impl<'de> Deserialize<'de> for ValidHeaderName {
+    fn deserialize<D>(deserializer: D) -> Result<ValidHeaderName, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        HeaderName::from_bytes(name.as_bytes())
+            .map(ValidHeaderName)
+            .map_err(|_| de::Error::custom(format!("`{}` is not a valid header name", name)))
+    }
+}
+
+///
+/// Header names & values, as given in the config file
+///
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderList(pub Vec<(HeaderName, String)>);
+
+impl<'de> Deserialize<'de> for HeaderList {
+    fn deserialize<D>(deserializer: D) -> Result<HeaderList, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = BTreeMap::<String, String>::deserialize(deserializer)?;
+        raw.into_iter()
+            .map(|(name, value)| {
+                let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                    de::Error::custom(format!("`{}` is not a valid header name", name))
+                })?;
+                HeaderValue::from_str(&value).map_err(|_| {
+                    de::Error::custom(format!("`{}` has an invalid value `{}`", name, value))
+                })?;
+                Ok((header_name, value))
+            })
+            .collect::<Result<Vec<_>, D::Error>>()
+            .map(HeaderList)
+    }
+}
+
+///
+/// The values that can be used within header values
+///
+#[derive(Debug, Clone, Default)]
+pub struct HeaderVars {
+    pub client_ip: String,
+    pub scheme: String,
+    pub host: String,
+}
+
+impl HeaderVars {
+    pub fn from_request(req: &HttpRequest<AppState>) -> HeaderVars {
+        let info = req.connection_info();
+        HeaderVars {
+            client_ip: req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            scheme: info.scheme().to_string(),
+            host: info.host().to_string(),
+        }
+    }
+    pub fn expand(&self, value: &str) -> String {
+        value
+            .replace("{client_ip}", &self.client_ip)
+            .replace("{scheme}", &self.scheme)
+            .replace("{host}", &self.host)
+    }
+}
+
+///
+/// The rules that apply to a single request, ready
+/// to be applied to a set of headers
+///
+#[derive(Debug, Clone, Default)]
+pub struct HeaderChanges {
+    rules: Vec<HeaderRule>,
+    vars: HeaderVars,
+}
+
+impl HeaderChanges {
+    pub fn new(rules: &[HeaderRule], path: &str, vars: HeaderVars) -> HeaderChanges {
+        HeaderChanges {
+            rules: rules.iter().filter(|r| r.matches(path)).cloned().collect(),
+            vars,
+        }
+    }
+    pub fn for_request(req: &HttpRequest<AppState>) -> HeaderChanges {
+        HeaderChanges::new(
+            &req.state().program_config.headers.request,
+            req.path(),
+            HeaderVars::from_request(req),
+        )
+    }
+    pub fn for_response(req: &HttpRequest<AppState>) -> HeaderChanges {
+        HeaderChanges::new(
+            &req.state().program_config.headers.response,
+            req.path(),
+            HeaderVars::from_request(req),
+        )
+    }
+
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate actix_web;
+    /// # extern crate bs;
+    /// # extern crate serde_yaml;
+    /// # use actix_web::http::HeaderMap;
+    /// # use bs::header_rules::*;
+    /// let rules: Vec<HeaderRule> = serde_yaml::from_str(r#"
+    /// - remove: [strict-transport-security]
+    ///   set: {cache-control: no-cache}
+    ///   add: {x-forwarded-for: "{client_ip}"}
+    /// "#).unwrap();
+    ///
+    /// let vars = HeaderVars { client_ip: "10.0.0.1".into(), ..HeaderVars::default() };
+    /// let changes = HeaderChanges::new(&rules, "/", vars);
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// headers.insert("strict-transport-security", "max-age=31536000".parse().unwrap());
+    /// headers.insert("cache-control", "max-age=3600".parse().unwrap());
+    /// changes.apply(&mut headers);
+    ///
+    /// assert!(headers.get("strict-transport-security").is_none());
+    /// assert_eq!(headers.get("cache-control").unwrap(), "no-cache");
+    /// assert_eq!(headers.get("x-forwarded-for").unwrap(), "10.0.0.1");
+    /// ```
+    ///
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for rule in self.rules.iter() {
+            for name in rule.remove.iter() {
+                headers.remove(&name.0);
+            }
+            for (name, value) in rule.set.0.iter() {
+                if let Ok(value) = HeaderValue::from_str(&self.vars.expand(value)) {
+                    headers.insert(name.clone(), value);
+                }
+            }
+            for (name, value) in rule.add.0.iter() {
+                if let Ok(value) = HeaderValue::from_str(&self.vars.expand(value)) {
+                    headers.append(name.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_parse_header_rules() {
+        let i = r#"
+request:
+  - paths: ["/checkout/*"]
+    set:
+      Fastly-Debug: "1"
+response:
+  - remove: [Strict-Transport-Security]
+        "#;
+        let rules: HeaderRules = serde_yaml::from_str(i).unwrap();
+        assert!(rules.request[0].matches("/checkout/cart"));
+        assert!(!rules.request[0].matches("/"));
+        assert_eq!(rules.response[0].remove.len(), 1);
+
+        let invalid = serde_yaml::from_str::<HeaderRules>("request: [{set: {'a b': c}}]");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_only_matching_rules_apply() {
+        let rules: Vec<HeaderRule> = serde_yaml::from_str(
+            r#"
+- paths: ["/checkout/*"]
+  add: {x-debug: "{scheme}://{host}"}
+        "#,
+        )
+        .unwrap();
+        let vars = HeaderVars {
+            scheme: "https".into(),
+            host: "127.0.0.1:8080".into(),
+            ..HeaderVars::default()
+        };
+
+        let mut headers = HeaderMap::new();
+        HeaderChanges::new(&rules, "/", vars.clone()).apply(&mut headers);
+        assert!(headers.is_empty());
+
+        HeaderChanges::new(&rules, "/checkout/cart", vars).apply(&mut headers);
+        assert_eq!(headers.get("x-debug").unwrap(), "https://127.0.0.1:8080");
+    }
+}
@@ -1,35 +1,223 @@
-use actix_web::http::Cookie;
+use actix_web::http::header;
 use actix_web::http::HeaderMap;
+use actix_web::HttpRequest;
+use app_state::AppState;
+use cookies::CookieRewrite;
+use csp::{rewrite_policy, CspMode};
+use http::header::HeaderName;
 use http::header::HeaderValue;
-use regex::Regex;
+use regex::{Captures, Regex};
+use rewrites::Matchers;
+use std::iter;
+use std::sync::Arc;
+use upstreams::ExtraUpstream;
 
 ///
-/// Clone a HeaderMap, whilst removing the domain
-/// from any set-cookies
+/// Clone a HeaderMap, whilst pointing any URLs for `target`
+/// at `replacer` and rewriting any set-cookies & security policies.
 ///
-pub fn clone_headers(headers: &HeaderMap, target: String, replacer: String) -> HeaderMap {
-    let matcher = format!("https?://{}", target);
-    let regex = Regex::new(&matcher).unwrap();
+/// `target` is a host (with an optional port) and `replacer` is a full
+/// origin, so that the scheme always matches the side receiving the headers.
+/// URLs for any extra upstreams in `rewrite` are pointed at their prefix
+///
+pub fn clone_headers(
+    headers: &HeaderMap,
+    target: String,
+    replacer: String,
+    rewrite: &HeaderRewrite,
+) -> HeaderMap {
+    let rewriter = HeaderUrls::new(&target, &replacer, rewrite);
     let mut hm = HeaderMap::new();
     for (key, value) in headers.iter().filter(|(key, _)| key.as_str() != "cookie") {
-        let strs = value.to_str().unwrap();
+        let rule = header_rule(key);
+
+        if rule == HeaderRule::Csp && rewrite.csp == CspMode::Strip {
+            continue;
+        }
 
-        let next_string = match key.as_str() {
-            "set-cookie" => {
-                let mut c = Cookie::parse_encoded(strs).unwrap();
-                c.set_domain("");
-                c.to_string()
+        let strs = match value.to_str() {
+            Ok(strs) => strs,
+            // not something we can rewrite
+            Err(..) => {
+                hm.append(key.clone(), value.clone());
+                continue;
             }
-            _ => strs.to_string(),
         };
 
-        let next = regex.replace(&next_string, replacer.as_str());
-        let hv = HeaderValue::from_str(&next);
-        hm.append(key.clone(), hv.unwrap());
+        let next_string = match rule {
+            HeaderRule::Cookie => rewrite.cookies.rewrite(strs),
+            HeaderRule::Csp => match rewrite.csp {
+                CspMode::Keep => strs.to_string(),
+                _ => rewrite_policy(strs, &target, &replacer, &rewrite.upstreams),
+            },
+            HeaderRule::Urls => rewriter.replace(strs, true),
+            HeaderRule::AbsoluteUrls => rewriter.replace(strs, false),
+        };
+
+        let hv = HeaderValue::from_str(&next_string).unwrap_or_else(|_| value.clone());
+        hm.append(key.clone(), hv);
     }
     hm
 }
 
+///
+/// Everything, beyond the target & local hosts, that
+/// decides how the headers of a single request are rewritten
+///
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrite {
+    pub cookies: CookieRewrite,
+    pub csp: CspMode,
+    ///
+    /// Only given for responses, since requests
+    /// are only ever made to one host
+    ///
+    pub upstreams: Vec<ExtraUpstream>,
+    pub matchers: Arc<Matchers>,
+}
+
+impl HeaderRewrite {
+    pub fn from_request(req: &HttpRequest<AppState>) -> HeaderRewrite {
+        HeaderRewrite {
+            cookies: CookieRewrite::from_request(req),
+            csp: req.state().program_config.csp,
+            upstreams: vec![],
+            matchers: req.state().matchers.clone(),
+        }
+    }
+    pub fn for_response(req: &HttpRequest<AppState>) -> HeaderRewrite {
+        HeaderRewrite {
+            upstreams: req.state().program_config.upstreams.clone(),
+            ..HeaderRewrite::from_request(req)
+        }
+    }
+}
+
+///
+/// How the value of a single header is rewritten
+///
+#[derive(Debug, PartialEq)]
+enum HeaderRule {
+    ///
+    /// Adjust the attributes to suit the local server
+    ///
+    Cookie,
+    ///
+    /// Swap the target for the local server in every
+    /// directive, or remove the header
+    ///
+    Csp,
+    ///
+    /// Headers that hold nothing but URLs, where protocol-relative
+    /// (`//host/path`) references are rewritten too.
+    ///
+    /// Relative URLs are left alone, as they already
+    /// resolve against whichever server sent them
+    ///
+    Urls,
+    ///
+    /// Everything else, only absolute URLs are rewritten
+    ///
+    AbsoluteUrls,
+}
+
+fn header_rule(name: &HeaderName) -> HeaderRule {
+    let url_headers = [
+        header::LOCATION,
+        header::CONTENT_LOCATION,
+        header::LINK,
+        header::REFRESH,
+        header::REFERER,
+        header::ORIGIN,
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    ];
+
+    if *name == header::SET_COOKIE {
+        HeaderRule::Cookie
+    } else if *name == header::CONTENT_SECURITY_POLICY
+        || *name == header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+    {
+        HeaderRule::Csp
+    } else if url_headers.contains(name) {
+        HeaderRule::Urls
+    } else {
+        HeaderRule::AbsoluteUrls
+    }
+}
+
+///
+/// Finds every reference to the target, or to an
+/// extra upstream, within a header value
+///
+struct HeaderUrls {
+    hosts: Vec<HostUrls>,
+}
+
+impl HeaderUrls {
+    fn new(target: &str, replacer: &str, rewrite: &HeaderRewrite) -> HeaderUrls {
+        let host = match replacer.find("://") {
+            Some(index) => &replacer[index + 3..],
+            None => replacer,
+        };
+        // extra upstreams go first, as they do for bodies
+        let hosts = rewrite
+            .upstreams
+            .iter()
+            .map(|upstream| HostUrls {
+                matcher: rewrite.matchers.header_urls(&upstream.host),
+                origin: format!("{}{}", replacer, upstream.prefix()),
+                host: format!("{}{}", host, upstream.prefix()),
+            })
+            .chain(iter::once(HostUrls {
+                matcher: rewrite.matchers.header_urls(target),
+                origin: replacer.to_string(),
+                host: host.to_string(),
+            }))
+            .collect();
+        HeaderUrls { hosts }
+    }
+    fn replace(&self, value: &str, protocol_relative: bool) -> String {
+        self.hosts.iter().fold(value.to_string(), |acc, host| {
+            host.replace(&acc, protocol_relative)
+        })
+    }
+}
+
+///
+/// Points the URLs for a single host at the local server
+///
+struct HostUrls {
+    matcher: Arc<Regex>,
+    origin: String,
+    host: String,
+}
+
+impl HostUrls {
+    fn replace(&self, value: &str, protocol_relative: bool) -> String {
+        self.matcher
+            .replace_all(value, |caps: &Captures| {
+                let end = caps.name("end").map_or("", |m| m.as_str());
+                let scheme = caps.name("scheme").map(|m| m.as_str().to_lowercase());
+                let port = caps.name("port").map(|m| m.as_str());
+
+                // any other port is another server on the same host
+                let same_server = match (scheme.as_ref().map(String::as_str), port) {
+                    (_, None) => true,
+                    (Some("http:"), Some(":80")) | (Some("https:"), Some(":443")) => true,
+                    _ => false,
+                };
+
+                match (scheme, protocol_relative, same_server) {
+                    (_, _, false) => caps[0].to_string(),
+                    (Some(..), _, true) => format!("{}{}", self.origin, end),
+                    (None, true, true) => format!("//{}{}", self.host, end),
+                    (None, false, true) => caps[0].to_string(),
+                }
+            })
+            .to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -51,6 +239,7 @@ mod tests {
             &hm,
             "www.acme.com".to_string(),
             "127.0.0.1:8080".to_string(),
+            &HeaderRewrite::default(),
         );
 
         // expected header map
@@ -72,9 +261,202 @@ mod tests {
             &hm,
             "www.acme.com".to_string(),
             "127.0.0.1:8080".to_string(),
+            &HeaderRewrite::default(),
         );
         let expected = HeaderMap::new();
 
         assert_eq!(expected, cloned);
     }
+
+    #[test]
+    pub fn test_rewrites_url_headers() {
+        let mut hm = HeaderMap::new();
+        hm.append(
+            "location",
+            "https://www.acme.com/customer/account/login/"
+                .parse()
+                .unwrap(),
+        );
+        hm.append(
+            "refresh",
+            "0; url=https://www.acme.com/checkout/".parse().unwrap(),
+        );
+        hm.append(
+            "link",
+            "<https://www.acme.com/a.css>; rel=preload, <//www.acme.com/b.js>; rel=preload, <https://www.acme.com.au/c.js>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+        hm.append("content-location", "/checkout/cart/".parse().unwrap());
+        hm.append("x-debug", "//www.acme.com/".parse().unwrap());
+
+        let cloned = clone_headers(
+            &hm,
+            "www.acme.com".to_string(),
+            "http://127.0.0.1:8080".to_string(),
+            &HeaderRewrite::default(),
+        );
+
+        let mut expected = HeaderMap::new();
+        expected.append(
+            "location",
+            "http://127.0.0.1:8080/customer/account/login/"
+                .parse()
+                .unwrap(),
+        );
+        expected.append(
+            "refresh",
+            "0; url=http://127.0.0.1:8080/checkout/".parse().unwrap(),
+        );
+        expected.append(
+            "link",
+            "<http://127.0.0.1:8080/a.css>; rel=preload, <//127.0.0.1:8080/b.js>; rel=preload, <https://www.acme.com.au/c.js>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+        expected.append("content-location", "/checkout/cart/".parse().unwrap());
+        expected.append("x-debug", "//www.acme.com/".parse().unwrap());
+
+        assert_eq!(expected, cloned);
+    }
+
+    #[test]
+    pub fn test_rewrites_with_ports() {
+        let mut hm = HeaderMap::new();
+        hm.append("referer", "http://127.0.0.1:8080/checkout".parse().unwrap());
+        hm.append("origin", "http://127.0.0.1:8080".parse().unwrap());
+        hm.append("x-other", "http://127.0.0.1:80801".parse().unwrap());
+
+        let cloned = clone_headers(
+            &hm,
+            "127.0.0.1:8080".to_string(),
+            "https://www.acme.com".to_string(),
+            &HeaderRewrite::default(),
+        );
+
+        let mut expected = HeaderMap::new();
+        expected.append("referer", "https://www.acme.com/checkout".parse().unwrap());
+        expected.append("origin", "https://www.acme.com".parse().unwrap());
+        expected.append("x-other", "http://127.0.0.1:80801".parse().unwrap());
+
+        assert_eq!(expected, cloned);
+    }
+
+    #[test]
+    pub fn test_rewrites_default_ports() {
+        let mut hm = HeaderMap::new();
+        hm.append(
+            "location",
+            "https://www.acme.com:443/login".parse().unwrap(),
+        );
+        hm.append(
+            "link",
+            "<http://www.acme.com:80/a.css>; rel=preload, <https://www.acme.com:8443/b.js>; rel=preload, <//www.acme.com:443/c.js>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+        hm.append("x-debug", "https://www.acme.com:80/".parse().unwrap());
+
+        let cloned = clone_headers(
+            &hm,
+            "www.acme.com".to_string(),
+            "http://127.0.0.1:8080".to_string(),
+            &HeaderRewrite::default(),
+        );
+
+        let mut expected = HeaderMap::new();
+        expected.append("location", "http://127.0.0.1:8080/login".parse().unwrap());
+        expected.append(
+            "link",
+            "<http://127.0.0.1:8080/a.css>; rel=preload, <https://www.acme.com:8443/b.js>; rel=preload, <//www.acme.com:443/c.js>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+        expected.append("x-debug", "https://www.acme.com:80/".parse().unwrap());
+
+        assert_eq!(expected, cloned);
+    }
+
+    #[test]
+    pub fn test_csp_modes() {
+        let mut hm = HeaderMap::new();
+        hm.append(
+            "content-security-policy",
+            "script-src 'self' https://www.acme.com".parse().unwrap(),
+        );
+        hm.append(
+            "content-security-policy-report-only",
+            "report-uri https://www.acme.com/csp".parse().unwrap(),
+        );
+
+        let clone = |csp| {
+            clone_headers(
+                &hm,
+                "www.acme.com".to_string(),
+                "http://127.0.0.1:8080".to_string(),
+                &HeaderRewrite {
+                    csp,
+                    ..HeaderRewrite::default()
+                },
+            )
+        };
+
+        let mut expected = HeaderMap::new();
+        expected.append(
+            "content-security-policy",
+            "script-src 'self' http://127.0.0.1:8080".parse().unwrap(),
+        );
+        expected.append(
+            "content-security-policy-report-only",
+            "report-uri http://127.0.0.1:8080/csp".parse().unwrap(),
+        );
+
+        assert_eq!(expected, clone(CspMode::Rewrite));
+        assert_eq!(hm, clone(CspMode::Keep));
+        assert_eq!(HeaderMap::new(), clone(CspMode::Strip));
+    }
+
+    #[test]
+    pub fn test_rewrites_upstream_urls() {
+        let mut hm = HeaderMap::new();
+        hm.append(
+            "location",
+            "https://media.acme.com/catalog/a.png".parse().unwrap(),
+        );
+        hm.append(
+            "link",
+            "<//static.acme.com/b.js>; rel=preload, <https://www.acme.com/c.css>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+
+        let cloned = clone_headers(
+            &hm,
+            "www.acme.com".to_string(),
+            "http://127.0.0.1:8080".to_string(),
+            &HeaderRewrite {
+                upstreams: vec![
+                    ExtraUpstream::new("media", "https://media.acme.com").unwrap(),
+                    ExtraUpstream::new("static", "https://static.acme.com").unwrap(),
+                ],
+                ..HeaderRewrite::default()
+            },
+        );
+
+        let mut expected = HeaderMap::new();
+        expected.append(
+            "location",
+            "http://127.0.0.1:8080/__bs/upstream/media/catalog/a.png"
+                .parse()
+                .unwrap(),
+        );
+        expected.append(
+            "link",
+            "<//127.0.0.1:8080/__bs/upstream/static/b.js>; rel=preload, <http://127.0.0.1:8080/c.css>; rel=preload"
+                .parse()
+                .unwrap(),
+        );
+
+        assert_eq!(expected, cloned);
+    }
 }
@@ -0,0 +1,308 @@
+use actix_web::http::header::HeaderValue;
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::{App, Error, HttpRequest, HttpResponse};
+use app_state::AppState;
+use bytes::Bytes;
+use futures::{stream, Future};
+use proxy_response::{handle_upstream_response, ResponseContext, UpstreamResponse};
+use proxy_transform::{proxy_transform, req_target};
+use serde::de::{self, Deserialize, Deserializer};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+///
+/// Added to every overridden response, with the
+/// local file that was served as its value
+///
+pub const OVERRIDE_HEADER: &str = "x-bs-override";
+
+///
+/// Serve a local file in place of the target's response.
+///
+/// `path` is a glob, and with `dir` the part of the path matched
+/// by the final `*` is looked up within that directory. Requests
+/// for files that don't exist locally still go to the target.
+///
+/// Files are served untouched, unless `rewrite` is set - they're
+/// then rewritten just like the target's response would have been
+///
+/// # Examples
+///
+/// ```yaml
+/// overrides:
+///   - path: /static/*/requirejs-config.js
+///     file: ./patched/requirejs-config.js
+///     rewrite: true
+///   - path: /static/*/Acme_Checkout/js/*
+///     dir: ./src/Acme/Checkout/view/frontend/web/js
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub path: String,
+    pub target: OverrideTarget,
+    pub rewrite: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverrideTarget {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+#[derive(Deserialize)]
+struct RawOverride {
+    path: String,
+    file: Option<String>,
+    dir: Option<String>,
+    #[serde(default)]
+    rewrite: bool,
+}
+
+impl<'de> Deserialize<'de> for Override {
+    fn deserialize<D>(deserializer: D) -> Result<Override, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawOverride::deserialize(deserializer)?;
+
+        if !raw.path.starts_with('/') || raw.path.contains('{') || raw.path.contains('}') {
+            return Err(de::Error::custom(format!(
+                "`{}` is not a valid override path, it should start with `/` and can contain `*` or `?`",
+                raw.path
+            )));
+        }
+
+        let target = match (raw.file, raw.dir) {
+            (Some(file), None) => OverrideTarget::File(PathBuf::from(file)),
+            (None, Some(ref dir)) if !raw.path.ends_with('*') => {
+                return Err(de::Error::custom(format!(
+                    "the override path `{}` should end with `*` to be used with `dir: {}`",
+                    raw.path, dir
+                )))
+            }
+            (None, Some(dir)) => OverrideTarget::Dir(PathBuf::from(dir)),
+            _ => {
+                return Err(de::Error::custom(format!(
+                    "the override for `{}` needs either a `file` or a `dir`",
+                    raw.path
+                )))
+            }
+        };
+
+        Ok(Override {
+            path: raw.path,
+            target,
+            rewrite: raw.rewrite,
+        })
+    }
+}
+
+impl Override {
+    ///
+    /// The path as an actix resource pattern, with
+    /// each wildcard becoming a named segment
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate bs;
+    /// # extern crate serde_yaml;
+    /// # use bs::overrides::Override;
+    /// let item: Override = serde_yaml::from_str("{path: /static/*/js/*, dir: ./build}").unwrap();
+    /// assert_eq!(item.route(), "/static/{w0:.*}/js/{w1:.*}");
+    /// ```
+    ///
+    pub fn route(&self) -> String {
+        let mut count = 0;
+        let mut route = String::new();
+        for c in self.path.chars() {
+            match c {
+                '*' | '?' => {
+                    let matcher = if c == '*' { ".*" } else { "." };
+                    route.push_str(&format!("{{w{}:{}}}", count, matcher));
+                    count += 1;
+                }
+                c => route.push(c),
+            }
+        }
+        route
+    }
+
+    ///
+    /// The local file for a request, `rest` being the
+    /// part of the path matched by the final wildcard
+    ///
+    pub fn file_for(&self, rest: &str) -> Option<PathBuf> {
+        match self.target {
+            OverrideTarget::File(ref file) => Some(file.clone()),
+            OverrideTarget::Dir(ref dir) => {
+                // never serve anything from outside the directory
+                if rest.split('/').any(|segment| segment == "..") {
+                    return None;
+                }
+                Some(dir.join(rest.trim_start_matches('/')))
+            }
+        }
+    }
+
+    fn last_wildcard(&self) -> String {
+        let count = self.path.chars().filter(|c| *c == '*' || *c == '?').count();
+        format!("w{}", count.saturating_sub(1))
+    }
+}
+
+///
+/// Register a resource for every override
+///
+pub fn add_overrides(app: App<AppState>, overrides: &[Override]) -> App<AppState> {
+    overrides.iter().cloned().fold(app, |app, item| {
+        let route = item.route();
+        app.resource(&route, move |r| r.f(move |req| handle_override(req, &item)))
+    })
+}
+
+pub fn handle_override(
+    req: &HttpRequest<AppState>,
+    item: &Override,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let rest = req
+        .match_info()
+        .get(&item.last_wildcard())
+        .unwrap_or("")
+        .to_string();
+
+    let file = match item.file_for(&rest) {
+        Some(file) => file,
+        None => return proxy_transform(req),
+    };
+
+    let body = match fs::read(&file) {
+        Ok(body) => body,
+        Err(e) => {
+            debug!("not overriding {}, {} {}", req.path(), file.display(), e);
+            return proxy_transform(req);
+        }
+    };
+
+    debug!("overriding {} with {}", req.path(), file.display());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(&file)),
+    );
+    if let Ok(value) = HeaderValue::from_str(&file.display().to_string()) {
+        headers.insert(OVERRIDE_HEADER, value);
+    }
+
+    let mut context = ResponseContext::new(req, req_target(req));
+    if !item.rewrite {
+        context.rewrite_rules = vec![];
+        context.regex_rewrites = vec![];
+        context.snippets = vec![];
+    }
+
+    let upstream = UpstreamResponse {
+        status: StatusCode::OK,
+        headers,
+        body: Box::new(stream::once(Ok(Bytes::from(body)))),
+    };
+
+    handle_upstream_response(upstream, context)
+}
+
+///
+/// The content types of the files that are commonly
+/// overridden when working on a storefront
+///
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match extension.as_ref().map(|ext| ext.as_str()) {
+        Some("js") => "application/javascript",
+        Some("json") | Some("map") => "application/json",
+        Some("css") => "text/css",
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_parse_overrides() {
+        let items: Vec<Override> = serde_yaml::from_str(
+            r#"
+- path: /static/*/requirejs-config.js
+  file: ./patched/requirejs-config.js
+- path: /static/*/js/*
+  dir: ./build
+  rewrite: true
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            items[0].target,
+            OverrideTarget::File("./patched/requirejs-config.js".into())
+        );
+        assert_eq!(items[0].last_wildcard(), "w0");
+        assert!(!items[0].rewrite);
+        assert_eq!(items[1].last_wildcard(), "w1");
+        assert!(items[1].rewrite);
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let invalid = vec![
+            "{path: /static/*}",
+            "{path: static/*, file: a.js}",
+            "{path: /static/js, dir: ./build}",
+            "{path: '/{version}/*', dir: ./build}",
+            "{path: /static/*, file: a.js, dir: ./build}",
+        ];
+        for yaml in invalid {
+            assert!(serde_yaml::from_str::<Override>(yaml).is_err(), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn test_file_for() {
+        let item: Override = serde_yaml::from_str("{path: /static/*/js/*, dir: ./build}").unwrap();
+        assert_eq!(
+            item.file_for("widget/cart.js"),
+            Some(PathBuf::from("./build/widget/cart.js"))
+        );
+        assert_eq!(item.file_for("../../etc/passwd"), None);
+
+        let item: Override = serde_yaml::from_str("{path: /static/*, file: ./a.js}").unwrap();
+        assert_eq!(item.file_for("anything.js"), Some(PathBuf::from("./a.js")));
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Path::new("requirejs-config.js")),
+            "application/javascript"
+        );
+        assert_eq!(content_type(Path::new("styles.CSS")), "text/css");
+        assert_eq!(
+            content_type(Path::new("bundle")),
+            "application/octet-stream"
+        );
+    }
+}
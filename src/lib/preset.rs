@@ -2,15 +2,23 @@ use actix_web::http::Method;
 use actix_web::{App, HttpRequest, HttpResponse};
 use app_state::AppState;
 use presets::m2::preset_m2::FutResp;
-use rewrites::RewriteContext;
+use rewrites::Rewriter;
 use serde_json;
+use snippets::Snippet;
 use std::fmt;
+use std::sync::Arc;
 
 pub trait Preset<T> {
     fn enhance(&self, app: App<T>) -> App<T>;
     fn rewrites(&self) -> RewriteFns {
         vec![]
     }
+    ///
+    /// Markup to add to every proxied HTML page
+    ///
+    fn snippets(&self) -> Vec<Snippet> {
+        vec![]
+    }
     fn add_before_middleware(&self, app: App<T>) -> App<T> {
         app
     }
@@ -31,7 +39,7 @@ pub trait PresetOptions {
 ///
 /// The following are just aliases
 ///
-pub type RewriteFns = Vec<fn(&str, &RewriteContext) -> String>;
+pub type RewriteFns = Vec<Arc<Rewriter>>;
 pub type ResourceDef = (String, Method, fn(&HttpRequest<AppState>) -> HttpResponse);
 pub type AsyncResourceDef = (String, Method, fn(&HttpRequest<AppState>) -> FutResp);
 
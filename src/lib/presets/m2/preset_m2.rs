@@ -12,6 +12,8 @@ use super::preset_m2_opts::M2PresetOptions;
 use super::replace_cookie_domain;
 use app_state::AppState;
 use preset::AsyncResourceDef;
+use snippets::Snippet;
+use std::sync::Arc;
 
 pub type FutResp = Box<Future<Item = HttpResponse, Error = Error>>;
 
@@ -110,7 +112,13 @@ impl Preset<AppState> for M2Preset {
     /// as it prevents session-based actions.
     ///
     fn rewrites(&self) -> RewriteFns {
-        vec![replace_cookie_domain::rewrite]
+        vec![Arc::new(replace_cookie_domain::rewrite)]
+    }
+    ///
+    /// Any snippets given in the preset's options
+    ///
+    fn snippets(&self) -> Vec<Snippet> {
+        self.options.snippets.clone()
     }
     ///
     /// a 'before' middleware is used to track incoming requests that contain
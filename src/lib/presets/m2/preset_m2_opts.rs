@@ -2,6 +2,7 @@ use config::ProgramConfig;
 use preset::PresetError;
 use preset::PresetOptions;
 use serde_json;
+use snippets::Snippet;
 
 #[derive(Deserialize, Debug)]
 pub struct M2PresetOptions {
@@ -14,6 +15,13 @@ pub struct M2PresetOptions {
     pub bundle_config: Option<String>,
     pub auth_basic: Option<AuthBasic>,
     pub module_blacklist: Option<Vec<String>>,
+
+    ///
+    /// Added to every page, alongside any
+    /// from the top level `snippets`
+    ///
+    #[serde(default)]
+    pub snippets: Vec<Snippet>,
 }
 
 fn default_require_path() -> Option<String> {
@@ -32,6 +40,7 @@ impl Default for M2PresetOptions {
             bundle_config: None,
             auth_basic: None,
             module_blacklist: None,
+            snippets: vec![],
         }
     }
 }
@@ -1,3 +1,4 @@
+use regex;
 use regex::Regex;
 use rewrites::RewriteContext;
 
@@ -5,7 +6,7 @@ use rewrites::RewriteContext;
 /// Remove an on-page cookie domain (usually in JSON blobs with Magento)
 ///
 pub fn rewrite(bytes: &str, context: &RewriteContext) -> String {
-    let matcher = format!(r#""domain": ".{}","#, context.host_to_replace);
+    let matcher = format!(r#""domain": ".{}","#, regex::escape(context.host_name()));
     Regex::new(&matcher)
         .unwrap()
         .replace_all(bytes, "")
@@ -35,11 +36,7 @@ mod tests {
     "#;
         let replaced = rewrite(
             &bytes,
-            &RewriteContext {
-                host_to_replace: String::from("www.acme.com"),
-                target_host: String::from("127.0.0.1"),
-                target_port: 80,
-            },
+            &RewriteContext::new("www.acme.com").with_target("127.0.0.1", 80),
         );
         println!("-> {}", replaced);
     }
@@ -0,0 +1,278 @@
+use actix_web::client::ClientResponse;
+use actix_web::error::PayloadError;
+use actix_web::http::uri::Uri;
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::{Body, Error, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::future::ok;
+use futures::{Future, Stream};
+
+use app_state::AppState;
+use charset::Charset;
+use content_encoding::ContentCoding;
+use disk_cache::CacheKey;
+use har::HarCapture;
+use header_rules::HeaderChanges;
+use headers::HeaderRewrite;
+use preset::RewriteFns;
+use proxy_transform::create_outgoing;
+use proxy_transform::get_host_port;
+use proxy_transform::upstream_url;
+use regex_rewrites::RegexRewrite;
+use rewrite_rules::{find_rule, media_type, RewriteRule};
+use rewrite_stream::RewriteStream;
+use rewrites::{Matchers, RewriteContext, Rewriter};
+use snippets::{Snippet, SnippetInjector};
+use std::sync::Arc;
+use upstreams::ExtraUpstream;
+
+///
+/// Everything needed to turn a response from the proxy
+/// target into a response for the original requester
+///
+pub struct ResponseContext {
+    pub req_uri: Uri,
+    pub req_host: String,
+    pub req_port: u16,
+    pub req_target: String,
+    pub req_scheme: String,
+    pub target_domain: String,
+    pub upstreams: Vec<ExtraUpstream>,
+    pub header_rewrite: HeaderRewrite,
+    pub header_changes: HeaderChanges,
+    pub har: HarCapture,
+    pub cache: Option<CacheKey>,
+    pub rewrites: RewriteFns,
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub regex_rewrites: Vec<RegexRewrite>,
+    pub snippets: Vec<Snippet>,
+    pub matchers: Arc<Matchers>,
+}
+
+impl ResponseContext {
+    pub fn new(incoming_request: &HttpRequest<AppState>, req_target: String) -> ResponseContext {
+        let state = incoming_request.state();
+        let (req_host, req_port) = get_host_port(incoming_request);
+        ResponseContext {
+            req_uri: incoming_request.uri().clone(),
+            req_host,
+            req_port,
+            req_target,
+            req_scheme: state.opts.scheme.to_string(),
+            target_domain: state.opts.target.clone(),
+            upstreams: state.program_config.upstreams.clone(),
+            header_rewrite: HeaderRewrite::for_response(incoming_request),
+            header_changes: HeaderChanges::for_response(incoming_request),
+            har: HarCapture::new(incoming_request, upstream_url(incoming_request)),
+            cache: CacheKey::for_request(incoming_request),
+            rewrites: state.rewrites.clone(),
+            rewrite_rules: state.program_config.rewrite_rules.clone(),
+            regex_rewrites: state.program_config.rewrites.clone(),
+            snippets: state
+                .snippets
+                .iter()
+                .chain(state.program_config.snippets.iter())
+                .cloned()
+                .collect(),
+            matchers: state.matchers.clone(),
+        }
+    }
+}
+
+///
+/// A response from the proxy target, either as it
+/// arrives or as it was stored in the disk cache
+///
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Box<Stream<Item = Bytes, Error = PayloadError>>,
+}
+
+impl UpstreamResponse {
+    pub fn from_client(resp: ClientResponse) -> UpstreamResponse {
+        UpstreamResponse {
+            status: resp.status(),
+            headers: resp.headers().clone(),
+            body: Box::new(resp.payload()),
+        }
+    }
+}
+
+///
+/// Send the response from the proxy target back, storing
+/// it in the disk cache when the request is cacheable
+///
+pub fn handle_proxy_response(
+    proxy_response: ClientResponse,
+    mut context: ResponseContext,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let upstream = UpstreamResponse::from_client(proxy_response);
+    let upstream = match context.cache.take() {
+        Some(key) => key.store(upstream),
+        None => upstream,
+    };
+    handle_upstream_response(upstream, context)
+}
+
+///
+/// Send a response back, either by streaming it
+/// untouched or by rewriting it along the way
+///
+pub fn handle_upstream_response(
+    upstream: UpstreamResponse,
+    context: ResponseContext,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    debug!("Got proxy response, status={}", upstream.status);
+    debug!(
+        "Got proxy response headers, headers={:#?}",
+        upstream.headers
+    );
+
+    // Bodies with a content-coding or charset that we can't
+    // decode are never modified
+    let coding = ContentCoding::from_headers(&upstream.headers);
+    let charset = Charset::from_headers(&upstream.headers);
+
+    // HTML pages are always rewritten when there are snippets to add
+    let fns = rewrites_for(&context, &upstream.headers).or_else(|| {
+        match is_html(&upstream.headers) && !context.snippets.is_empty() {
+            true => Some(vec![]),
+            false => None,
+        }
+    });
+
+    // If we decide to modify the response, the body is rewritten
+    // as it streams through (text content only)
+    match (fns, coding, charset) {
+        (Some(fns), Some(coding), charset) if charset != Charset::Unsupported => {
+            debug!("attempting to rewrite body, coding={:?}", coding);
+            response_from_rewrite(upstream, context, fns, coding, charset)
+        }
+        // If we get here, we decided not to re-write the response
+        // so we just stream it back to the client
+        _ => pass_through_response(upstream, context),
+    }
+}
+
+/// Pass-through response
+fn pass_through_response(
+    upstream: UpstreamResponse,
+    context: ResponseContext,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let mut resp = create_outgoing(
+        &upstream.status,
+        &upstream.headers,
+        context.target_domain,
+        context.req_target,
+        &context.header_rewrite,
+        &context.header_changes,
+    )
+    .finish();
+
+    let body = context.har.tap_response(
+        upstream.body.from_err(),
+        upstream.status,
+        &upstream.headers,
+        None,
+        &resp,
+    );
+    resp.set_body(Body::Streaming(Box::new(body)));
+
+    Box::new(ok(resp))
+}
+
+///
+/// Create a response, rewriting the body received from the
+/// proxy target as it streams through
+///
+fn response_from_rewrite(
+    upstream: UpstreamResponse,
+    context: ResponseContext,
+    mut fns: RewriteFns,
+    coding: ContentCoding,
+    charset: Charset,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    // Snippets go in last, so that no other rewrite can change them
+    if is_html(&upstream.headers) {
+        if let Some(injector) = SnippetInjector::new(&context.snippets, context.req_uri.path()) {
+            fns.push(Arc::new(injector));
+        }
+    }
+
+    // Extra upstreams may use a different scheme to the target, but
+    // they're all served locally with the same one
+    let rewrite_context = RewriteContext::new(context.target_domain.clone())
+        .with_target(context.req_host, context.req_port)
+        .with_scheme(context.req_scheme)
+        .with_upstreams(context.upstreams)
+        .with_matchers(context.matchers);
+
+    // The rewritten body will differ in length, so the upstream
+    // value cannot be forwarded. The original Content-Encoding is kept
+    // since the body is re-encoded with it
+    let mut resp_headers = upstream.headers.clone();
+    resp_headers.remove(header::CONTENT_LENGTH);
+
+    debug!("creating response");
+
+    let mut resp = create_outgoing(
+        &upstream.status,
+        &resp_headers,
+        context.target_domain,
+        context.req_target,
+        &context.header_rewrite,
+        &context.header_changes,
+    )
+    .finish();
+
+    // The body is tapped on both sides of the rewrite, so
+    // that the HAR entry can show the difference
+    let (upstream_body, upstream_copy) = context.har.tap_upstream(upstream.body);
+    let rewritten =
+        RewriteStream::new(upstream_body, rewrite_context, fns, coding, charset).from_err();
+    let body = context.har.tap_response(
+        rewritten,
+        upstream.status,
+        &upstream.headers,
+        upstream_copy,
+        &resp,
+    );
+    resp.set_body(Body::Streaming(Box::new(body)));
+
+    Box::new(ok(resp))
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| media_type(value) == "text/html")
+}
+
+///
+/// Determine if the current response should be rewritten, by finding
+/// the first of the configured [RewriteRule]s that matches it.
+///
+/// Any [RegexRewrite]s that match are applied too, even when no
+/// rule does. `None` means the response is passed through untouched
+///
+fn rewrites_for(context: &ResponseContext, headers: &HeaderMap) -> Option<RewriteFns> {
+    let path = context.req_uri.path();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok());
+
+    let config_rewrites: RewriteFns = context
+        .regex_rewrites
+        .iter()
+        .filter(|rewrite| rewrite.matches(path, content_type))
+        .map(|rewrite| Arc::new(rewrite.clone()) as Arc<Rewriter>)
+        .collect();
+
+    match find_rule(&context.rewrite_rules, path, content_type) {
+        Some(rule) => Some(rule.rewrite_fns(config_rewrites, &context.rewrites)),
+        None if !config_rewrites.is_empty() => Some(config_rewrites),
+        None => None,
+    }
+}
@@ -1,19 +1,22 @@
-use actix::Actor;
-use actix_web::client::ClientConnector;
 use actix_web::client::ClientRequestBuilder;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_web::http::StatusCode;
-use actix_web::http::{header, HeaderMap, Method};
-use actix_web::{client, dev, http, Error, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::http::{header, ContentEncoding, HeaderMap};
+use actix_web::{client, dev, Error, HttpMessage, HttpRequest, HttpResponse};
 use app_state::AppState;
 use base64::encode;
+use config::ProgramConfig;
+use disk_cache::CacheKey;
 use futures::Future;
-use headers::clone_headers;
-use openssl::ssl::SslConnector;
-use openssl::ssl::{SslMethod, SslVerifyMode};
+use header_rules::HeaderChanges;
+use headers::{clone_headers, HeaderRewrite};
 use presets::m2::preset_m2_opts::{AuthBasic, M2PresetOptions};
+use proxy_response::{handle_upstream_response, ResponseContext};
 use std::str;
+use upstreams::find_upstream;
 use with_body::forward_request_with_body;
 use without_body::forward_request_without_body;
+use ws_proxy::{is_websocket_upgrade, proxy_websocket};
 
 ///
 /// This function will clone incoming requests
@@ -22,75 +25,212 @@ use without_body::forward_request_without_body;
 pub fn proxy_transform(
     original_request: &HttpRequest<AppState>,
 ) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    if is_websocket_upgrade(original_request.headers()) {
+        return proxy_websocket(original_request);
+    }
+
+    // A hard refresh in the browser always goes to the target,
+    // and updates the cache with whatever it sends back
+    let stored = CacheKey::for_request(original_request)
+        .filter(|key| !is_no_cache(original_request.headers()) && key.is_stored());
+
+    match stored {
+        Some(key) => {
+            let req = original_request.clone();
+            Box::new(key.lookup().then(move |cached| match cached {
+                Ok(Some(upstream)) => {
+                    debug!("serving {} from the disk cache", req.path());
+                    let context = ResponseContext::new(&req, req_target(&req));
+                    handle_upstream_response(upstream, context)
+                }
+                _ => forward(&req),
+            }))
+        }
+        None => forward(original_request),
+    }
+}
+
+///
+/// Send the request on to the target
+///
+fn forward(
+    original_request: &HttpRequest<AppState>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let req_target = req_target(original_request);
     let outgoing = proxy_req_setup(original_request);
-    let bind_port = original_request.state().opts.port;
-    let scheme = &original_request.state().opts.scheme;
-    let (host, port) = get_host_port(original_request, bind_port);
-    let req_target = format!("{}://{}:{}", scheme, host, port);
-
-    match *original_request.method() {
-        Method::POST => forward_request_with_body(original_request, req_target, outgoing),
-        _ => forward_request_without_body(original_request, req_target, outgoing),
+
+    match has_body(original_request.headers()) {
+        true => forward_request_with_body(original_request, req_target, outgoing),
+        false => forward_request_without_body(original_request, req_target, outgoing),
     }
 }
 
+///
+/// The local address that the request was made to, which
+/// links to the target are rewritten to point at
+///
+pub fn req_target(req: &HttpRequest<AppState>) -> String {
+    let (host, port) = get_host_port(req);
+    format!("{}://{}:{}", req.state().opts.scheme, host, port)
+}
+
+///
+/// Any request (regardless of the method) that carries a payload
+/// needs its body forwarding
+///
+/// # Examples
+///
+/// ```
+/// # extern crate actix_web;
+/// # extern crate bs;
+/// # use actix_web::http::HeaderMap;
+/// # use bs::proxy_transform::has_body;
+/// let mut headers = HeaderMap::new();
+/// assert_eq!(has_body(&headers), false);
+///
+/// headers.insert("content-length", "0".parse().unwrap());
+/// assert_eq!(has_body(&headers), false);
+///
+/// headers.insert("content-length", "27".parse().unwrap());
+/// assert_eq!(has_body(&headers), true);
+/// ```
+///
+pub fn has_body(headers: &HeaderMap) -> bool {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let chunked = headers
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.to_lowercase().contains("chunked"));
+
+    content_length > 0 || chunked
+}
+
+fn is_no_cache(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.contains("no-cache"))
+}
+
 pub fn proxy_req_setup(original_request: &HttpRequest<AppState>) -> ClientRequestBuilder {
     debug!(
         "incoming proxy_req = {:?}",
         original_request.connection_info().host()
     );
     let original_req_headers = original_request.headers().clone();
-    let cloned = clone_headers(
+    let mut cloned = clone_headers(
         &original_req_headers,
         original_request.connection_info().host().to_string(),
-        original_request.state().opts.target.clone(),
+        format!(
+            "{}://{}",
+            original_request.state().opts.scheme,
+            original_request.state().opts.target
+        ),
+        &HeaderRewrite::from_request(original_request),
     );
 
     // build up the next outgoing URL (for the back-end)
-    let next_url = format!(
-        "{}://{}{}{}",
-        original_request.state().opts.scheme,
-        original_request.state().opts.target,
-        original_request.path(),
-        match original_request.uri().query().as_ref() {
-            Some(q) => format!("?{}", q),
-            None => "".to_string(),
-        }
-    );
+    let next_url = upstream_url(original_request);
 
     debug!("next_url={}", next_url);
 
     let mut outgoing = client::ClientRequest::build();
 
-    // Since this is a development tool only, we're being risky here
-    // and just disabling all SSL verifications
-    let mut ssl_conn = SslConnector::builder(SslMethod::tls()).unwrap();
-    ssl_conn.set_verify(SslVerifyMode::NONE);
-
-    let conn = ClientConnector::with_connector(ssl_conn.build()).start();
+    let conn = original_request.state().connector.clone();
 
+    // Bodies are decoded (if at all) by the rewriters, everything else
+    // must reach the browser exactly as the upstream sent it
     outgoing
         .with_connector(conn)
+        .disable_decompress()
         .method(original_request.method().clone())
         .uri(next_url);
 
+    // ensure the 'host' header is re-written, requests for
+    // extra upstreams are sent with their own host
+    let upstreams = &original_request.state().program_config.upstreams;
+    let host = match find_upstream(upstreams, original_request.path()) {
+        Some((upstream, _)) => upstream.host.clone(),
+        None => original_request.state().opts.target.clone(),
+    };
+    set_header(&mut cloned, header::HOST, host);
+
+    set_header(
+        &mut cloned,
+        header::ORIGIN,
+        format!(
+            "{}://{}",
+            original_request.state().opts.scheme,
+            original_request.state().opts.target
+        ),
+    );
+
+    set_header(
+        &mut cloned,
+        header::COOKIE,
+        join_cookies(&original_req_headers),
+    );
+
+    //
+    // Add basic auth header if auth_basic is present in the options
+    //
+    basic_auth_header(&original_request.state().program_config).map(|auth| {
+        set_header(&mut cloned, header::AUTHORIZATION, auth);
+    });
+
+    //
+    // Any rules from the config file come last, so
+    // that they can override everything above
+    //
+    HeaderChanges::for_request(original_request).apply(&mut cloned);
+
     for (key, value) in cloned.iter() {
         outgoing.header(key.clone(), value.clone());
     }
 
-    // ensure the 'host' header is re-written
-    outgoing.set_header(
-        http::header::HOST,
-        original_request.state().opts.target.clone(),
-    );
+    outgoing
+}
 
-    outgoing.set_header(
-        http::header::ORIGIN,
-        original_request.state().opts.target.clone(),
-    );
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    match HeaderValue::from_str(&value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(..) => debug!("invalid value for {}, {:?}", name, value),
+    }
+}
+
+///
+/// The URL on the proxy target that a request is forwarded to
+///
+pub fn upstream_url(original_request: &HttpRequest<AppState>) -> String {
+    let state = original_request.state();
+    let query = match original_request.uri().query().as_ref() {
+        Some(q) => format!("?{}", q),
+        None => "".to_string(),
+    };
+    match find_upstream(&state.program_config.upstreams, original_request.path()) {
+        Some((upstream, path)) => format!("{}{}{}", upstream.origin(), path, query),
+        None => format!(
+            "{}://{}{}{}",
+            state.opts.scheme,
+            state.opts.target,
+            original_request.path(),
+            query
+        ),
+    }
+}
 
-    // combine all cookie headers into a single one
-    let joined_cookie = original_req_headers
+///
+/// Combine all cookie headers into a single one
+///
+pub fn join_cookies(headers: &HeaderMap) -> String {
+    headers
         .get_all(header::COOKIE)
         .iter()
         .map(|hdr| {
@@ -98,24 +238,20 @@ pub fn proxy_req_setup(original_request: &HttpRequest<AppState>) -> ClientReques
             s.to_string()
         })
         .collect::<Vec<String>>()
-        .join("; ");
-
-    outgoing.set_header(http::header::COOKIE, joined_cookie);
+        .join("; ")
+}
 
-    //
-    // Add basic auth header if auth_basic is present in the options
-    //
-    M2PresetOptions::get_opts(&original_request.state().program_config).map(|opts| {
+///
+/// The `Authorization` header value, if auth_basic
+/// is present in the options
+///
+pub fn basic_auth_header(program_config: &ProgramConfig) -> Option<String> {
+    M2PresetOptions::get_opts(program_config).and_then(|opts| {
         opts.auth_basic.map(|auth: AuthBasic| {
             let combined = format!("{}:{}", auth.username, auth.password);
-            outgoing.set_header(
-                http::header::AUTHORIZATION,
-                format!("Basic {}", encode(&combined)),
-            );
-        });
-    });
-
-    outgoing
+            format!("Basic {}", encode(&combined))
+        })
+    })
 }
 
 pub fn create_outgoing(
@@ -123,10 +259,16 @@ pub fn create_outgoing(
     resp_headers: &HeaderMap,
     target: String,
     replacer: String,
+    rewrite: &HeaderRewrite,
+    changes: &HeaderChanges,
 ) -> dev::HttpResponseBuilder {
     let mut outgoing = HttpResponse::Ok();
     outgoing.status(*status_code);
-    let c = clone_headers(resp_headers, target, replacer);
+    // Bodies are either passed through as-is or re-encoded by
+    // the rewriters, so the server must never compress them again
+    outgoing.content_encoding(ContentEncoding::Identity);
+    let mut c = clone_headers(resp_headers, target, replacer, rewrite);
+    changes.apply(&mut c);
     debug!("Headers for response = {:#?}", c);
     // Copy headers from backend response to main response
     for (key, value) in c.iter() {
@@ -135,13 +277,46 @@ pub fn create_outgoing(
     outgoing
 }
 
-pub fn get_host_port(incoming_request: &HttpRequest<AppState>, bind_port: u16) -> (String, u16) {
-    let info = incoming_request.connection_info();
-    let split: Vec<&str> = info.host().split(":").collect();
+///
+/// The host & port that the request was made to, as the
+/// browser sees them - this can be any of the bound addresses
+///
+pub fn get_host_port(incoming_request: &HttpRequest<AppState>) -> (String, u16) {
+    let default_port = incoming_request.state().opts.scheme.default_port();
+    split_host_port(incoming_request.connection_info().host(), default_port)
+}
+
+///
+/// Split a `Host` header into the host and port, IPv6
+/// addresses keep their brackets so they can be used in URLs
+///
+/// # Examples
+///
+/// ```
+/// use bs::proxy_transform::split_host_port;
+///
+/// assert_eq!(split_host_port("192.168.0.10:8080", 443), ("192.168.0.10".to_string(), 8080));
+/// assert_eq!(split_host_port("[fe80::1]:8080", 443), ("[fe80::1]".to_string(), 8080));
+/// assert_eq!(split_host_port("[::1]", 443), ("[::1]".to_string(), 443));
+/// assert_eq!(split_host_port("localhost", 80), ("localhost".to_string(), 80));
+/// ```
+///
+pub fn split_host_port(host: &str, default_port: u16) -> (String, u16) {
+    let (host, port) = match host.rfind(']') {
+        Some(end) => (&host[..=end], host[end + 1..].trim_start_matches(':')),
+        None => match host.matches(':').count() {
+            1 => {
+                let index = host.find(':').expect("has a colon");
+                (&host[..index], &host[index + 1..])
+            }
+            _ => (host, ""),
+        },
+    };
+
+    let host = match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    };
 
-    match (split.get(0), split.get(1)) {
-        (Some(h), Some(p)) => (h.to_string(), p.parse().expect("parsed port")),
-        (Some(h), None) => (h.to_string(), bind_port),
-        _ => ("127.0.0.1".to_string(), bind_port),
-    }
+    (host, port.parse().unwrap_or(default_port))
 }
@@ -1,11 +1,20 @@
 use actix_web::client::ClientResponse;
-use actix_web::{AsyncResponder, Error, HttpMessage, HttpRequest};
+use actix_web::http::header;
+use actix_web::{AsyncResponder, Error, HttpMessage, HttpRequest, HttpResponse};
 use app_state::AppState;
+use bytes::Bytes;
+use charset::transform_text;
+use content_encoding::{decode_body, ContentCoding};
 use futures::Future;
+use header_rules::HeaderChanges;
+use headers::HeaderRewrite;
 use presets::m2::preset_m2::FutResp;
 use proxy_transform::create_outgoing;
 use proxy_transform::get_host_port;
 use proxy_transform::proxy_req_setup;
+use proxy_transform::upstream_url;
+use std::time::Instant;
+use upstream_error::{failure_response, send_error_response, FailureKind, UpstreamFailure};
 
 ///
 /// A helper for applying a transformation on a proxy
@@ -17,34 +26,65 @@ where
 {
     let mut outgoing = proxy_req_setup(original_request);
     let target_domain = original_request.state().opts.target.clone();
-    let bind_port = original_request.state().opts.port;
-    let (host, port) = get_host_port(original_request, bind_port);
+    let (host, port) = get_host_port(original_request);
+    let scheme = original_request.state().opts.scheme.clone();
+    let started = Instant::now();
+    let req = original_request.clone();
+    let url = upstream_url(original_request);
+    let header_rewrite = HeaderRewrite::for_response(original_request);
+    let header_changes = HeaderChanges::for_response(original_request);
 
     outgoing
         .finish()
         .unwrap()
         .send()
-        .map_err(Error::from)
-        .and_then(move |proxy_response: ClientResponse| {
-            proxy_response
-                .body()
-                .limit(1_000_000)
-                .from_err()
-                .and_then(move |body| {
-                    use std::str;
+        .then(move |res| -> FutResp {
+            let proxy_response: ClientResponse = match res {
+                Ok(proxy_response) => proxy_response,
+                Err(e) => return send_error_response(&req, url, e, started),
+            };
+            let output = proxy_response.body().limit(1_000_000).then(
+                move |res| -> Result<HttpResponse, Error> {
+                    let body = match res {
+                        Ok(body) => body,
+                        Err(e) => {
+                            let kind = FailureKind::from_payload_error(&e);
+                            let failure =
+                                UpstreamFailure::new(&req, url, kind, e.to_string(), started);
+                            return Ok(failure_response(&req, failure));
+                        }
+                    };
+                    let req_target = format!("{}://{}:{}", scheme, host, port);
+                    let mut resp_headers = proxy_response.headers().clone();
 
-                    let req_target = format!("{}:{}", host, port);
-                    let body_content = str::from_utf8(&body[..]).unwrap();
-                    let next_body: String = String::from(body_content);
+                    // The transformed body is always sent back uncompressed,
+                    // unknown codings (or corrupt bodies) are passed back untouched.
+                    // The same goes for text that can't be decoded
+                    let decoded = ContentCoding::from_headers(&resp_headers)
+                        .and_then(|coding| decode_body(coding, &body).ok());
+
+                    let next_body = match decoded {
+                        Some(decoded) => {
+                            resp_headers.remove(header::CONTENT_ENCODING);
+                            resp_headers.remove(header::CONTENT_LENGTH);
+                            Bytes::from(transform_text(&resp_headers, decoded, &f))
+                        }
+                        None => body,
+                    };
 
                     Ok(create_outgoing(
                         &proxy_response.status(),
-                        &proxy_response.headers(),
+                        &resp_headers,
                         target_domain.to_string(),
                         req_target,
+                        &header_rewrite,
+                        &header_changes,
                     )
-                    .body(f(next_body)))
-                })
+                    .body(next_body))
+                },
+            );
+
+            Box::new(output)
         })
         .responder()
 }
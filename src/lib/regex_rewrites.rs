@@ -0,0 +1,149 @@
+use glob::Glob;
+use regex::Regex;
+use rewrite_rules::media_type;
+use rewrites::{RewriteContext, Rewriter};
+use serde::de::{self, Deserialize, Deserializer};
+
+///
+/// A find & replace on response bodies, given in the config file.
+///
+/// `replace` can refer to capture groups with `$1` or `$name`.
+/// Bodies are rewritten line by line, so a match can't span lines.
+///
+/// An empty `paths` list matches anything. Without `content_types`
+/// only text is rewritten, see [DEFAULT_CONTENT_TYPES]
+///
+/// # Examples
+///
+/// ```yaml
+/// rewrites:
+///   - match: 'data-mage-init=.(\{"Acme_Tracking/js/[a-z]+":)'
+///     replace: 'data-disabled=$1'
+///     content_types: ["text/html"]
+///   - match: "https://cdn.acme.com/fonts/"
+///     replace: "/static/fonts/"
+///     paths: ["/static/*.css"]
+/// ```
+///
+#[derive(Deserialize, Debug, Clone)]
+pub struct RegexRewrite {
+    #[serde(rename = "match")]
+    pub matcher: ValidRegex,
+
+    pub replace: String,
+
+    #[serde(default)]
+    pub paths: Vec<Glob>,
+
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<Glob>,
+}
+
+///
+/// The responses a rewrite applies to when it doesn't say - never
+/// images, fonts or anything else that's not text
+///
+pub const DEFAULT_CONTENT_TYPES: &[&str] =
+    &["text/*", "application/javascript", "application/json"];
+
+fn default_content_types() -> Vec<Glob> {
+    DEFAULT_CONTENT_TYPES
+        .iter()
+        .map(|t| Glob::new(t).expect("valid glob"))
+        .collect()
+}
+
+impl RegexRewrite {
+    pub fn matches(&self, path: &str, content_type: Option<&str>) -> bool {
+        let path_matches = self.paths.is_empty() || self.paths.iter().any(|g| g.matches(path));
+
+        let type_matches = self.content_types.is_empty()
+            || content_type
+                .map(media_type)
+                .map_or(false, |t| self.content_types.iter().any(|g| g.matches(&t)));
+
+        path_matches && type_matches
+    }
+}
+
+///
+/// # Examples
+///
+/// ```
+/// # extern crate bs;
+/// # extern crate serde_yaml;
+/// use bs::regex_rewrites::RegexRewrite;
+/// use bs::rewrites::{RewriteContext, Rewriter};
+///
+/// let rewrite: RegexRewrite = serde_yaml::from_str(r#"
+/// match: 'src="/static/(version\d+)/'
+/// replace: 'src="/static/$1-local/'
+/// "#).unwrap();
+///
+/// assert_eq!(
+///     rewrite.rewrite(r#"<script src="/static/version123/app.js">"#, &RewriteContext::default()),
+///     r#"<script src="/static/version123-local/app.js">"#
+/// );
+/// ```
+///
+impl Rewriter for RegexRewrite {
+    fn rewrite(&self, input: &str, _context: &RewriteContext) -> String {
+        self.matcher
+            .0
+            .replace_all(input, self.replace.as_str())
+            .to_string()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidRegex(pub Regex);
+
+impl<'de> Deserialize<'de> for ValidRegex {
+    fn deserialize<D>(deserializer: D) -> Result<ValidRegex, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(ValidRegex)
+            .map_err(|e| de::Error::custom(format!("`{}` is not a valid regex, {}", pattern, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_parse_regex_rewrites() {
+        let i = r#"
+- match: "(?P<name>Acme)"
+  replace: "$name Local"
+  content_types: ["text/html"]
+  paths: ["/checkout/*"]
+        "#;
+        let rewrites: Vec<RegexRewrite> = serde_yaml::from_str(i).unwrap();
+        assert!(rewrites[0].matches("/checkout/cart", Some("text/html; charset=UTF-8")));
+        assert!(!rewrites[0].matches("/", Some("text/html")));
+        assert!(!rewrites[0].matches("/checkout/cart", Some("application/json")));
+        assert_eq!(
+            rewrites[0].rewrite("<title>Acme</title>", &RewriteContext::default()),
+            "<title>Acme Local</title>"
+        );
+
+        let invalid = serde_yaml::from_str::<RegexRewrite>("{match: '(', replace: ''}");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_default_content_types() {
+        let rewrite: RegexRewrite = serde_yaml::from_str("{match: Acme, replace: Local}").unwrap();
+        assert!(rewrite.matches("/", Some("text/css")));
+        assert!(rewrite.matches("/", Some("application/javascript; charset=UTF-8")));
+        assert!(rewrite.matches("/", Some("application/json")));
+        assert!(!rewrite.matches("/", Some("image/png")));
+        assert!(!rewrite.matches("/", Some("font/woff2")));
+        assert!(!rewrite.matches("/", None));
+    }
+}
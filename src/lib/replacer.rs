@@ -1,4 +1,5 @@
-use rewrites::RewriteContext;
+use rewrites::{RewriteContext, Rewriter};
+use std::sync::Arc;
 
 ///
 /// # Examples
@@ -21,14 +22,14 @@ impl Subject {
 }
 
 impl Replacer for Subject {
-    type Item = fn(&str, &Self::Options) -> String;
+    type Item = Arc<Rewriter>;
     type Options = RewriteContext;
     type Output = String;
 
     fn apply(self, opts: &Self::Options, items: Vec<Self::Item>) -> Self::Output {
         items
             .iter()
-            .fold(self.0, |acc, item_fn| item_fn(&acc, &opts))
+            .fold(self.0, |acc, rewriter| rewriter.rewrite(&acc, &opts))
     }
 }
 
@@ -40,17 +41,13 @@ mod tests {
     #[test]
     fn test_subject_replacer() {
         let s = Subject::new(r#"<a href="https://acme.m2/path">Click</a>"#);
-        let ctx = RewriteContext {
-            host_to_replace: String::from("acme.m2"),
-            target_host: String::from("127.0.0.1"),
-            target_port: 8080,
-        };
+        let ctx = RewriteContext::new("acme.m2").with_target("127.0.0.1", 8080);
         fn replacer(input: &str, opts: &RewriteContext) -> String {
             Regex::new(&opts.host_to_replace)
                 .unwrap()
                 .replace_all(input, opts.target_host.as_str())
                 .to_string()
         }
-        let _updated = s.apply(&ctx, vec![replacer]);
+        let _updated = s.apply(&ctx, vec![Arc::new(replacer)]);
     }
 }
@@ -0,0 +1,526 @@
+use actix::actors::resolver::{Connect, ResolverError};
+use actix::{Actor, Context, Handler, ResponseFuture};
+use actix_web::http::{header, ContentEncoding, StatusCode};
+use actix_web::{server, App, HttpRequest, HttpResponse};
+use base64;
+use config::ProgramStartError;
+use futures::Future;
+use har::NameValue;
+use options::ProxyScheme;
+use serde_json;
+use ssl;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_tcp::TcpStream;
+use url::Url;
+
+///
+/// How many recorded URLs are suggested when
+/// a request doesn't match any of them
+///
+const NEAREST_COUNT: usize = 5;
+
+///
+/// The responses from a recorded session (see `--record-har`),
+/// as the target originally sent them.
+///
+/// HAR files exported from browser devtools work too, although
+/// their bodies are always served uncompressed
+///
+#[derive(Clone, PartialEq)]
+pub struct Recording {
+    pub entries: Vec<RecordedEntry>,
+}
+
+impl fmt::Debug for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Recording {{ {} entries }}", self.entries.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEntry {
+    pub method: String,
+    pub url: String,
+    ///
+    /// Including the port, when it's not the default one
+    ///
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    pub headers: Vec<NameValue>,
+    pub body: Vec<u8>,
+}
+
+impl Recording {
+    pub fn from_file(path: &str) -> Result<Recording, String> {
+        fs::read_to_string(path)
+            .map_err(|e| format!("could not read `{}`, {}", path, e))
+            .and_then(|json| Recording::from_json(&json))
+    }
+
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bs::replay::Recording;
+    ///
+    /// let recording = Recording::from_json(r#"{"log": {"entries": [{
+    ///     "request": {"method": "GET", "url": "https://www.acme.com/"},
+    ///     "response": {"status": 200, "headers": [], "content": {"text": "<p>home</p>"}}
+    /// }]}}"#).unwrap();
+    ///
+    /// assert_eq!(recording.origin(), Some("https://www.acme.com".to_string()));
+    /// assert_eq!(recording.find("GET", "www.acme.com", "/").unwrap().body, b"<p>home</p>");
+    /// assert!(recording.find("GET", "www.acme.com", "/checkout").is_none());
+    /// ```
+    ///
+    pub fn from_json(json: &str) -> Result<Recording, String> {
+        let file: HarFile =
+            serde_json::from_str(json).map_err(|e| format!("not a valid HAR file, {}", e))?;
+
+        let total = file.log.entries.len();
+        let entries = file
+            .log
+            .entries
+            .into_iter()
+            .filter_map(RecordedEntry::from_har)
+            .collect::<Vec<RecordedEntry>>();
+
+        if entries.len() < total {
+            warn!(
+                "{} of {} recorded entries can't be replayed, since their body is missing or incomplete",
+                total - entries.len(),
+                total
+            );
+        }
+
+        match (entries.is_empty(), total) {
+            (true, 0) => Err("the recording has no entries".to_string()),
+            (true, _) => Err(
+                "none of the recorded entries have a complete body, record with --har-bodies to replay a session"
+                    .to_string(),
+            ),
+            (false, _) => Ok(Recording { entries }),
+        }
+    }
+
+    ///
+    /// The target that was recorded, taken from the first entry
+    ///
+    pub fn origin(&self) -> Option<String> {
+        self.entries.get(0).and_then(|entry| {
+            Url::parse(&entry.url)
+                .ok()
+                .map(|url| url.origin().ascii_serialization())
+        })
+    }
+
+    ///
+    /// The most recent response for a request, requests for
+    /// another host are only used when there's no exact match
+    ///
+    pub fn find(&self, method: &str, host: &str, path: &str) -> Option<&RecordedEntry> {
+        let matching = |entry: &&RecordedEntry| entry.method == method && entry.path == path;
+        self.entries
+            .iter()
+            .rev()
+            .filter(matching)
+            .find(|entry| entry.host == host)
+            .or_else(|| self.entries.iter().rev().find(matching))
+    }
+
+    ///
+    /// The recorded URLs that share the longest start with `path`
+    ///
+    pub fn nearest(&self, path: &str) -> Vec<&str> {
+        let mut scored: Vec<(usize, &str)> = vec![];
+        for entry in self.entries.iter() {
+            if scored.iter().any(|&(_, url)| url == entry.url) {
+                continue;
+            }
+            let score = path
+                .chars()
+                .zip(entry.path.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            scored.push((score, entry.url.as_str()));
+        }
+        // stable, so equal scores keep the recorded order
+        scored.sort_by(|a, b| b.0.cmp(&a.0));
+        scored
+            .into_iter()
+            .take(NEAREST_COUNT)
+            .map(|(_, url)| url)
+            .collect()
+    }
+}
+
+impl RecordedEntry {
+    ///
+    /// `None` for entries that can't be served as they were
+    /// recorded, like those without a (complete) body
+    ///
+    fn from_har(entry: HarFileEntry) -> Option<RecordedEntry> {
+        let url_string = entry.request.upstream_url.unwrap_or(entry.request.url);
+        let url = Url::parse(&url_string).ok()?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str()?, port),
+            None => url.host_str()?.to_string(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        //
+        // Only the target's own body (under `_upstream`) is kept as it
+        // was sent, `content` is always the decoded version of a body
+        //
+        let response = entry.response;
+        let (status, headers, content, decoded) = match response.upstream {
+            Some(upstream) => match upstream.content {
+                Some(content) => (upstream.status, upstream.headers, content, false),
+                None => (upstream.status, upstream.headers, response.content, true),
+            },
+            None => (response.status, response.headers, response.content, true),
+        };
+
+        // See `har_content`, the body is only part of what was sent
+        // or it's still compressed (without saying so any more)
+        let incomplete = content.comment.as_ref().map_or(false, |comment| {
+            comment.starts_with("truncated") || comment.starts_with("could not be decoded")
+        });
+        if incomplete {
+            return None;
+        }
+
+        // Without a body, only responses that never have one can
+        // be replayed - and nothing was recorded to be decoded
+        let method = entry.request.method.to_uppercase();
+        let bodiless = method == "HEAD" || is_bodiless(status);
+        if content.text.is_none() && !bodiless {
+            return None;
+        }
+        let decoded = decoded || content.text.is_none();
+
+        // The body is sent in one go
+        let headers = headers
+            .into_iter()
+            .filter(|h| {
+                let name = h.name.to_lowercase();
+                name != "content-length"
+                    && name != "transfer-encoding"
+                    && !(decoded && name == "content-encoding")
+            })
+            .collect();
+
+        let body = match (content.text, content.encoding.as_ref().map(|e| e.as_str())) {
+            (Some(text), Some("base64")) => base64::decode(&text).ok()?,
+            (Some(text), _) => text.into_bytes(),
+            (None, _) => vec![],
+        };
+
+        Some(RecordedEntry {
+            method,
+            url: url_string,
+            host,
+            path,
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+///
+/// Responses that are complete without a body, so
+/// can be replayed from a recording that has none
+///
+fn is_bodiless(status: u16) -> bool {
+    match status {
+        100..=199 | 204 | 304 => true,
+        301 | 302 | 303 | 307 | 308 => true,
+        _ => false,
+    }
+}
+
+#[derive(Deserialize)]
+struct HarFile {
+    log: HarFileLog,
+}
+
+#[derive(Deserialize)]
+struct HarFileLog {
+    entries: Vec<HarFileEntry>,
+}
+
+#[derive(Deserialize)]
+struct HarFileEntry {
+    request: HarFileRequest,
+    response: HarFileResponse,
+}
+
+#[derive(Deserialize)]
+struct HarFileRequest {
+    method: String,
+    url: String,
+    #[serde(rename = "_upstreamUrl", default)]
+    upstream_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HarFileResponse {
+    status: u16,
+    #[serde(default)]
+    headers: Vec<NameValue>,
+    #[serde(default)]
+    content: HarFileContent,
+    #[serde(rename = "_upstream", default)]
+    upstream: Option<HarFileUpstream>,
+}
+
+#[derive(Deserialize)]
+struct HarFileUpstream {
+    status: u16,
+    #[serde(default)]
+    headers: Vec<NameValue>,
+    #[serde(default)]
+    content: Option<HarFileContent>,
+}
+
+#[derive(Deserialize, Default)]
+struct HarFileContent {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    encoding: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
+///
+/// Answer a request from the recording, in place of the target
+///
+pub fn handle_replay(req: &HttpRequest<Arc<Recording>>) -> HttpResponse {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => "/",
+    };
+
+    match req.state().find(req.method().as_str(), host, path) {
+        Some(entry) => {
+            let status = StatusCode::from_u16(entry.status).unwrap_or(StatusCode::OK);
+            let mut resp = HttpResponse::build(status);
+            // bodies are served exactly as they were recorded
+            resp.content_encoding(ContentEncoding::Identity);
+            for h in entry.headers.iter() {
+                resp.header(h.name.as_str(), h.value.as_str());
+            }
+            resp.body(entry.body.clone())
+        }
+        None => HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(not_recorded_page(
+                req.method().as_str(),
+                path,
+                &req.state().nearest(path),
+            )),
+    }
+}
+
+fn not_recorded_page(method: &str, path: &str, nearest: &[&str]) -> String {
+    let items = nearest
+        .iter()
+        .map(|url| format!("        <li><a href=\"{url}\">{url}</a></li>", url = url))
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="UTF-8">
+<title>404 - not recorded - config-gen</title>
+</head>
+<body>
+    <h1>This request was not recorded</h1>
+    <p><code>{method} {path}</code></p>
+    <p>The nearest recorded URLs are:</p>
+    <ul>
+{items}
+    </ul>
+</body>
+</html>
+"#,
+        method = method,
+        path = path.replace('<', "&lt;"),
+        items = items,
+    )
+}
+
+///
+/// Serve the recording from a local address, using TLS when the
+/// recorded target did, so that the proxy can connect to it as usual
+///
+pub fn start_replay_server(
+    recording: Arc<Recording>,
+    scheme: &ProxyScheme,
+) -> Result<SocketAddr, ProgramStartError> {
+    let s = server::new(move || {
+        App::with_state(recording.clone()).default_resource(|r| r.f(handle_replay))
+    })
+    .workers(1);
+
+    let s = match *scheme {
+        ProxyScheme::Http => s.bind("127.0.0.1:0").map_err(ProgramStartError::BindHttp)?,
+        ProxyScheme::Https => s
+            .bind_ssl("127.0.0.1:0", ssl::builder()?)
+            .map_err(ProgramStartError::BindHttps)?,
+    };
+
+    let addr = s.addrs().get(0).cloned().ok_or(ProgramStartError::Ip)?;
+    s.shutdown_timeout(0).start();
+
+    Ok(addr)
+}
+
+///
+/// Used by the [ClientConnector] in place of the regular DNS resolver,
+/// so that every connection (whatever the host) goes to the replay server
+///
+pub struct ReplayResolver {
+    addr: SocketAddr,
+}
+
+impl ReplayResolver {
+    pub fn new(addr: SocketAddr) -> ReplayResolver {
+        ReplayResolver { addr }
+    }
+}
+
+impl Actor for ReplayResolver {
+    type Context = Context<Self>;
+}
+
+impl Handler<Connect> for ReplayResolver {
+    type Result = ResponseFuture<TcpStream, ResolverError>;
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Context<Self>) -> Self::Result {
+        debug!("replaying {} from {}", msg.name, self.addr);
+        Box::new(TcpStream::connect(&self.addr).map_err(ResolverError::IoError))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorded_fixture() {
+        let recording = Recording::from_file("test/fixtures/recording.har").unwrap();
+        assert_eq!(recording.origin(), Some("https://www.acme.com".to_string()));
+
+        // the body as the target sent it, not the rewritten one
+        let home = recording.find("GET", "www.acme.com", "/").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&home.body),
+            r#"<a href="https://www.acme.com/checkout">Checkout</a>"#
+        );
+        assert!(home.headers.iter().all(|h| h.name != "content-length"));
+
+        let logo = recording
+            .find("GET", "media.acme.com", "/logo.png?v=2")
+            .unwrap();
+        assert_eq!(logo.body, b"PNG");
+    }
+
+    #[test]
+    fn test_nearest() {
+        let recording = Recording::from_file("test/fixtures/recording.har").unwrap();
+        assert_eq!(
+            recording.nearest("/log"),
+            vec![
+                "https://media.acme.com/logo.png?v=2",
+                "https://www.acme.com/",
+                "https://www.acme.com/checkout/cart/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decoded_bodies_are_served_uncompressed() {
+        let recording = Recording::from_json(
+            r#"{"log": {"entries": [{
+            "request": {"method": "GET", "url": "http://127.0.0.1:8080/"},
+            "response": {
+                "status": 200,
+                "headers": [{"name": "content-encoding", "value": "gzip"}],
+                "content": {"text": "<p>home</p>"},
+                "_upstream": {"status": 200, "headers": [{"name": "Content-Encoding", "value": "gzip"}]}
+            }
+        }]}}"#,
+        )
+        .unwrap();
+        let home = &recording.entries[0];
+        assert_eq!(home.body, b"<p>home</p>");
+        assert!(home.headers.is_empty());
+    }
+
+    #[test]
+    fn test_entries_without_a_body() {
+        let recording = Recording::from_json(
+            r#"{"log": {"entries": [
+            {
+                "request": {"method": "GET", "url": "https://www.acme.com/"},
+                "response": {"status": 200, "headers": [{"name": "content-encoding", "value": "br"}], "content": {}}
+            },
+            {
+                "request": {"method": "GET", "url": "https://www.acme.com/app.js"},
+                "response": {"status": 200, "content": {"text": "var", "comment": "truncated to 5000000 bytes"}}
+            },
+            {
+                "request": {"method": "GET", "url": "https://www.acme.com/checkout"},
+                "response": {"status": 302, "headers": [
+                    {"name": "location", "value": "/login"},
+                    {"name": "content-encoding", "value": "gzip"}
+                ], "content": {}}
+            }
+        ]}}"#,
+        )
+        .unwrap();
+
+        assert!(recording.find("GET", "www.acme.com", "/").is_none());
+        assert!(recording.find("GET", "www.acme.com", "/app.js").is_none());
+
+        let redirect = recording.find("GET", "www.acme.com", "/checkout").unwrap();
+        assert_eq!(redirect.body, b"");
+        assert_eq!(
+            redirect.headers,
+            vec![NameValue {
+                name: "location".into(),
+                value: "/login".into()
+            }]
+        );
+
+        let bodiless = Recording::from_json(
+            r#"{"log": {"entries": [{
+            "request": {"method": "GET", "url": "https://www.acme.com/"},
+            "response": {"status": 200, "content": {"size": -1}}
+        }]}}"#,
+        );
+        assert!(bodiless.unwrap_err().contains("--har-bodies"));
+    }
+
+    #[test]
+    fn test_invalid_recordings() {
+        assert!(Recording::from_json("{}").is_err());
+        assert!(Recording::from_json(r#"{"log": {"entries": []}}"#).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use glob::Glob;
+use preset::RewriteFns;
+use rewrites::replace_host;
+use std::sync::Arc;
+
+///
+/// Decides which responses have their bodies rewritten,
+/// and which rewrites are applied to them.
+///
+/// Rules are checked in order and the first match wins, a response
+/// that matches no rule is streamed back untouched.
+///
+/// An empty `paths` or `content_types` list matches anything
+///
+/// # Examples
+///
+/// ```yaml
+/// rewrite_rules:
+///   - content_types: ["text/html"]
+///   - content_types: ["application/json"]
+///     paths: ["/customer/section/load*"]
+///     rewrites: [host]
+/// ```
+///
+#[derive(Deserialize, Debug, Clone)]
+pub struct RewriteRule {
+    #[serde(default)]
+    pub paths: Vec<Glob>,
+
+    ///
+    /// Matched against the lowercase media type only,
+    /// so `text/html;charset=UTF-8` matches `text/html`
+    ///
+    #[serde(default)]
+    pub content_types: Vec<Glob>,
+
+    #[serde(default = "default_rewrites")]
+    pub rewrites: Vec<RewriteKind>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteKind {
+    ///
+    /// Replace links to the target with links to the local server
+    ///
+    Host,
+    ///
+    /// Any rewrites added by presets
+    ///
+    Presets,
+}
+
+fn default_rewrites() -> Vec<RewriteKind> {
+    vec![RewriteKind::Host, RewriteKind::Presets]
+}
+
+///
+/// The rules that are used when none are given
+/// in the config file
+///
+pub fn default_rewrite_rules() -> Vec<RewriteRule> {
+    vec![
+        RewriteRule {
+            paths: vec![Glob::new("*requirejs-config.js*").expect("valid glob")],
+            content_types: vec![],
+            rewrites: default_rewrites(),
+        },
+        RewriteRule {
+            paths: vec![],
+            content_types: vec![Glob::new("text/html").expect("valid glob")],
+            rewrites: default_rewrites(),
+        },
+        RewriteRule {
+            paths: vec![],
+            content_types: vec![Glob::new("application/json").expect("valid glob")],
+            rewrites: vec![RewriteKind::Host],
+        },
+    ]
+}
+
+impl RewriteRule {
+    pub fn matches(&self, path: &str, content_type: Option<&str>) -> bool {
+        let path_matches = self.paths.is_empty() || self.paths.iter().any(|g| g.matches(path));
+
+        let type_matches = self.content_types.is_empty()
+            || content_type
+                .map(media_type)
+                .map_or(false, |t| self.content_types.iter().any(|g| g.matches(&t)));
+
+        path_matches && type_matches
+    }
+    ///
+    /// Build the list of rewriters for this rule, always in the
+    /// order `host` -> `config_rewrites` -> `presets`
+    ///
+    pub fn rewrite_fns(
+        &self,
+        config_rewrites: RewriteFns,
+        preset_rewrites: &RewriteFns,
+    ) -> RewriteFns {
+        let mut fns: RewriteFns = vec![];
+        if self.rewrites.contains(&RewriteKind::Host) {
+            fns.push(Arc::new(replace_host));
+        }
+        fns.extend(config_rewrites);
+        if self.rewrites.contains(&RewriteKind::Presets) {
+            fns.extend(preset_rewrites.iter().cloned());
+        }
+        fns
+    }
+}
+
+///
+/// Find the first rule that matches a response
+///
+pub fn find_rule<'a>(
+    rules: &'a [RewriteRule],
+    path: &str,
+    content_type: Option<&str>,
+) -> Option<&'a RewriteRule> {
+    rules.iter().find(|rule| rule.matches(path, content_type))
+}
+
+///
+/// Strip any parameters from a content-type
+///
+/// # Examples
+///
+/// ```
+/// use bs::rewrite_rules::media_type;
+///
+/// assert_eq!(media_type("text/html;charset=UTF-8"), "text/html");
+/// assert_eq!(media_type(" Application/JSON "), "application/json");
+/// ```
+///
+pub fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = default_rewrite_rules();
+        let html = find_rule(&rules, "/", Some("text/html; charset=utf-8"));
+        assert!(html.is_some());
+        let html = find_rule(&rules, "/", Some("text/html;charset=UTF-8"));
+        assert!(html.is_some());
+        let config = find_rule(
+            &rules,
+            "/static/frontend/Acme/default/en_GB/requirejs-config.js",
+            Some("application/javascript"),
+        );
+        assert!(config.is_some());
+        let json = find_rule(&rules, "/rest/V1/carts/mine", Some("application/json"));
+        assert_eq!(json.unwrap().rewrites, vec![RewriteKind::Host]);
+        let js = find_rule(&rules, "/static/app.js", Some("application/javascript"));
+        assert!(js.is_none());
+        let unknown = find_rule(&rules, "/", None);
+        assert!(unknown.is_none());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let i = r#"
+- content_types: ["application/json"]
+  paths: ["/customer/section/load*"]
+  rewrites: [host]
+- content_types: ["text/css"]
+        "#;
+        let rules: Vec<RewriteRule> = serde_yaml::from_str(i).unwrap();
+        let json = find_rule(&rules, "/customer/section/load/", Some("application/json"));
+        assert_eq!(json.unwrap().rewrites, vec![RewriteKind::Host]);
+        let css = find_rule(&rules, "/static/styles.css", Some("text/css"));
+        assert_eq!(
+            css.unwrap().rewrites,
+            vec![RewriteKind::Host, RewriteKind::Presets]
+        );
+        assert!(find_rule(&rules, "/other", Some("application/json")).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_fns_order() {
+        use replacer::{Replacer, Subject};
+        use rewrites::RewriteContext;
+
+        fn preset(input: &str, _context: &RewriteContext) -> String {
+            input.replace("127.0.0.1:8080/local", "preset")
+        }
+        fn config(input: &str, _context: &RewriteContext) -> String {
+            input.replace("127.0.0.1:8080", "127.0.0.1:8080/local")
+        }
+
+        let rule = &default_rewrite_rules()[1];
+        let preset_rewrites: RewriteFns = vec![Arc::new(preset)];
+        let fns = rule.rewrite_fns(vec![Arc::new(config)], &preset_rewrites);
+
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let output = Subject::new("https://www.acme.com").apply(&context, fns);
+        assert_eq!(output, "https://preset");
+    }
+}
@@ -0,0 +1,452 @@
+use bytes::Bytes;
+use charset::{self, Charset, SNIFF_LIMIT};
+use content_encoding::{Coder, ContentCoding};
+use encoding_rs::Encoding;
+use futures::{Async, Poll, Stream};
+use preset::RewriteFns;
+use rewrites::RewriteContext;
+use std::io;
+use std::mem;
+
+///
+/// Wraps a stream of body chunks and applies the [RewriteFns]
+/// to them as they pass through, rather than buffering the entire
+/// body into memory first.
+///
+/// Chunks are split on line boundaries: everything up to & including
+/// the last newline is rewritten & sent on, the remainder is held back
+/// until the next chunk arrives. This means a match can never be cut in half
+/// by a chunk boundary, as long as it does not span multiple lines.
+///
+/// Very long lines (minified HTML, JSON on a single line) are cut at
+/// the end of a tag or a word instead, see [MAX_PENDING]
+///
+/// Compressed bodies are decoded before rewriting and then
+/// encoded again using the same [ContentCoding]. Text is decoded
+/// using the [Charset] of the body, lines that cannot be decoded are
+/// passed through untouched
+///
+pub struct RewriteStream<S> {
+    inner: S,
+    rewriter: Option<BodyRewriter>,
+}
+
+impl<S> RewriteStream<S> {
+    pub fn new(
+        inner: S,
+        context: RewriteContext,
+        fns: RewriteFns,
+        coding: ContentCoding,
+        charset: Charset,
+    ) -> RewriteStream<S> {
+        RewriteStream {
+            inner,
+            rewriter: Some(BodyRewriter {
+                decoder: Coder::decoder(coding),
+                lines: LineRewriter::new(context, fns, charset),
+                encoder: Coder::encoder(coding),
+            }),
+        }
+    }
+}
+
+impl<S> Stream for RewriteStream<S>
+where
+    S: Stream<Item = Bytes>,
+    S::Error: From<io::Error>,
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.rewriter.is_none() {
+                return Ok(Async::Ready(None));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => {
+                    let next = match self.rewriter {
+                        Some(ref mut r) => r.write(&chunk)?,
+                        None => chunk,
+                    };
+                    // nothing complete to send yet, wait for more data
+                    if !next.is_empty() {
+                        return Ok(Async::Ready(Some(next)));
+                    }
+                }
+                Async::Ready(None) => {
+                    let remaining = match self.rewriter.take() {
+                        Some(r) => r.finish()?,
+                        None => Bytes::new(),
+                    };
+                    return match remaining.is_empty() {
+                        false => Ok(Async::Ready(Some(remaining))),
+                        true => Ok(Async::Ready(None)),
+                    };
+                }
+            }
+        }
+    }
+}
+
+///
+/// decode -> rewrite -> encode
+///
+struct BodyRewriter {
+    decoder: Coder,
+    lines: LineRewriter,
+    encoder: Coder,
+}
+
+impl BodyRewriter {
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let decoded = self.decoder.write(chunk)?;
+        let rewritten = self.lines.write(&decoded);
+        self.encoder.write(&rewritten).map(Bytes::from)
+    }
+    fn finish(mut self) -> io::Result<Bytes> {
+        let decoded = self.decoder.finish()?;
+        let mut rewritten = self.lines.write(&decoded).to_vec();
+        rewritten.extend_from_slice(&self.lines.finish());
+        let mut output = self.encoder.write(&rewritten)?;
+        output.extend(self.encoder.finish()?);
+        Ok(Bytes::from(output))
+    }
+}
+
+///
+/// How the raw bytes of each line are turned into text
+///
+enum Decode {
+    ///
+    /// No charset was declared, so the start of the body is buffered
+    /// until it can be checked for a `<meta charset>`
+    ///
+    Sniffing,
+    Text(&'static Encoding),
+    PassThrough,
+}
+
+///
+/// How much of a line is held back before it's cut
+/// somewhere other than a newline
+///
+const MAX_PENDING: usize = 64 * 1024;
+
+///
+/// Held back when a long line has nowhere safe to be cut,
+/// no rewrite is expected to match more than this at once
+///
+const PENDING_TAIL: usize = 4 * 1024;
+
+///
+/// Buffers incomplete lines and applies rewrites
+/// to complete ones.
+///
+/// Note: splitting on a newline byte is safe since only
+/// ASCII-compatible encodings are ever decoded
+///
+pub struct LineRewriter {
+    pending: Vec<u8>,
+    context: RewriteContext,
+    fns: RewriteFns,
+    decode: Decode,
+}
+
+impl LineRewriter {
+    pub fn new(context: RewriteContext, fns: RewriteFns, charset: Charset) -> LineRewriter {
+        LineRewriter {
+            pending: vec![],
+            context,
+            fns,
+            decode: match charset {
+                Charset::Declared(encoding) => Decode::Text(encoding),
+                Charset::Undeclared => Decode::Sniffing,
+                Charset::Unsupported => Decode::PassThrough,
+            },
+        }
+    }
+    ///
+    /// Accept the next chunk, returning whatever can
+    /// safely be rewritten so far
+    ///
+    pub fn write(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        if let Decode::Sniffing = self.decode {
+            if self.pending.len() < SNIFF_LIMIT {
+                return Bytes::new();
+            }
+            self.decode = self.sniffed();
+        }
+        if let Decode::PassThrough = self.decode {
+            return Bytes::from(mem::replace(&mut self.pending, vec![]));
+        }
+        let split = match self.pending.iter().rposition(|b| *b == b'\n') {
+            Some(index) => Some(index + 1),
+            None if self.pending.len() > MAX_PENDING => Some(safe_split(&self.pending)),
+            None => None,
+        };
+        match split {
+            Some(index) => {
+                let rest = self.pending.split_off(index);
+                let complete = mem::replace(&mut self.pending, rest);
+                self.rewrite(complete)
+            }
+            None => Bytes::new(),
+        }
+    }
+    ///
+    /// The upstream body has ended, so whatever is
+    /// left over can now be rewritten
+    ///
+    pub fn finish(mut self) -> Bytes {
+        if let Decode::Sniffing = self.decode {
+            self.decode = self.sniffed();
+        }
+        let remaining = mem::replace(&mut self.pending, vec![]);
+        let mut output = self.rewrite(remaining).to_vec();
+        if let Decode::Text(encoding) = self.decode {
+            let held = self.apply(String::new(), true);
+            output.extend_from_slice(&encoding.encode(&held).0);
+        }
+        Bytes::from(output)
+    }
+    fn sniffed(&self) -> Decode {
+        match charset::sniff(&self.pending) {
+            Some(encoding) => Decode::Text(encoding),
+            None => Decode::PassThrough,
+        }
+    }
+    ///
+    /// Lines that cannot be decoded are passed
+    /// through untouched
+    ///
+    fn rewrite(&self, segment: Vec<u8>) -> Bytes {
+        let encoding = match self.decode {
+            Decode::Text(encoding) => encoding,
+            _ => return Bytes::from(segment),
+        };
+        let mut output = vec![];
+        let mut text = String::new();
+        let mut start = 0;
+        while start < segment.len() {
+            let end = segment[start..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(segment.len(), |index| start + index + 1);
+            let line = &segment[start..end];
+            match encoding.decode_without_bom_handling_and_without_replacement(line) {
+                Some(decoded) => text.push_str(&decoded),
+                None => {
+                    // anything held back from before the line goes first
+                    let next = self.apply(mem::replace(&mut text, String::new()), true);
+                    output.extend_from_slice(&encoding.encode(&next).0);
+                    output.extend_from_slice(line);
+                }
+            }
+            start = end;
+        }
+        let next = self.apply(text, false);
+        output.extend_from_slice(&encoding.encode(&next).0);
+        Bytes::from(output)
+    }
+    ///
+    /// Run every rewriter over the text in turn. Whatever one of them
+    /// flushes still goes through the ones after it
+    ///
+    fn apply(&self, text: String, flush: bool) -> String {
+        self.fns.iter().fold(text, |acc, rewriter| {
+            let mut next = rewriter.rewrite(&acc, &self.context);
+            if flush {
+                next.push_str(&rewriter.flush(&self.context));
+            }
+            next
+        })
+    }
+}
+
+///
+/// Where a long line can be cut without splitting a match - just after
+/// the end of a tag, some whitespace or a quote, since none of those
+/// can be part of a URL. The bytes are all ASCII, so the cut
+/// can't land within a character either
+///
+fn safe_split(pending: &[u8]) -> usize {
+    let boundary = pending
+        .iter()
+        .rposition(|b| *b == b'>')
+        .or_else(|| pending.iter().rposition(|b| b.is_ascii_whitespace()))
+        .or_else(|| pending.iter().rposition(|b| *b == b'"'));
+
+    match boundary {
+        Some(index) => index + 1,
+        None => {
+            let tail = pending.len().saturating_sub(PENDING_TAIL);
+            let mut index = tail;
+            while index > 0 && !pending[index - 1].is_ascii() {
+                index -= 1;
+            }
+            match index {
+                0 => tail,
+                _ => index,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+    use futures::stream::iter_ok;
+    use futures::Future;
+    use rewrites::replace_host;
+    use snippets::{Snippet, SnippetInjector, SnippetPosition};
+    use std::sync::Arc;
+
+    fn rewrite(chunks: Vec<Vec<u8>>, coding: ContentCoding) -> Vec<u8> {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let input = iter_ok::<_, io::Error>(chunks.into_iter().map(Bytes::from));
+        let output = RewriteStream::new(
+            input,
+            context,
+            vec![Arc::new(replace_host)],
+            coding,
+            Charset::Undeclared,
+        )
+        .concat2()
+        .wait()
+        .expect("stream completes");
+        output.to_vec()
+    }
+
+    fn run(chunks: Vec<&'static str>) -> String {
+        let chunks = chunks.into_iter().map(|c| c.as_bytes().to_vec()).collect();
+        String::from_utf8(rewrite(chunks, ContentCoding::Identity)).expect("bytes->String")
+    }
+
+    #[test]
+    fn test_rewrites_across_chunk_boundaries() {
+        let actual = run(vec![
+            "<a href=\"https://www.ac",
+            "me.com/path\">Home</a>\n<a href=\"http:",
+            "//www.acme.com\">Home</a>",
+        ]);
+        let expected = "<a href=\"https://127.0.0.1:8080/path\">Home</a>\n<a href=\"http://127.0.0.1:8080\">Home</a>";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_rewrites_gzipped_body() {
+        let mut encoder = Coder::encoder(ContentCoding::Gzip);
+        let mut gzipped = encoder
+            .write(b"<a href=\"https://www.acme.com\">Home</a>\n")
+            .unwrap();
+        gzipped.extend(encoder.finish().unwrap());
+
+        let chunks = gzipped.chunks(5).map(|c| c.to_vec()).collect();
+        let output = rewrite(chunks, ContentCoding::Gzip);
+
+        let mut decoder = Coder::decoder(ContentCoding::Gzip);
+        let mut actual = decoder.write(&output).unwrap();
+        actual.extend(decoder.finish().unwrap());
+        assert_eq!(
+            String::from_utf8(actual).unwrap(),
+            "<a href=\"https://127.0.0.1:8080\">Home</a>\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrites_with_sniffed_charset() {
+        let input = b"<meta charset=\"iso-8859-1\">\ncaf\xe9 https://www.acme.com\n".to_vec();
+        let output = rewrite(vec![input], ContentCoding::Identity);
+        assert_eq!(
+            output,
+            b"<meta charset=\"iso-8859-1\">\ncaf\xe9 https://127.0.0.1:8080\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_passes_through_undecodable_lines() {
+        let input = b"caf\xe9 https://www.acme.com\nhttps://www.acme.com\n".to_vec();
+        let output = rewrite(vec![input], ContentCoding::Identity);
+        assert_eq!(
+            output,
+            b"caf\xe9 https://www.acme.com\nhttps://127.0.0.1:8080\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_snippets_around_undecodable_lines() {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let snippets = vec![Snippet::html(SnippetPosition::BodyEnd, "<b>end</b>")];
+        let injector = SnippetInjector::new(&snippets, "/").unwrap();
+        let fns: RewriteFns = vec![Arc::new(replace_host), Arc::new(injector)];
+        let mut rewriter = LineRewriter::new(context, fns, Charset::Declared(UTF_8));
+
+        let mut output = rewriter
+            .write(b"<p>https://www.acme.com</p></body>\n\xff\xfe\n</html>\n")
+            .to_vec();
+        output.extend_from_slice(&rewriter.finish());
+
+        assert_eq!(
+            output,
+            b"<p>https://127.0.0.1:8080</p><b>end</b></body>\n\xff\xfe\n</html>\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_holds_back_incomplete_lines() {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let mut rewriter = LineRewriter::new(
+            context,
+            vec![Arc::new(replace_host)],
+            Charset::Declared(UTF_8),
+        );
+        assert_eq!(
+            rewriter.write(b"line 1\nhttps://www.acme"),
+            Bytes::from("line 1\n")
+        );
+        assert_eq!(rewriter.write(b".com"), Bytes::new());
+        assert_eq!(rewriter.finish(), Bytes::from("https://127.0.0.1:8080"));
+    }
+
+    #[test]
+    fn test_streams_long_lines() {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let mut rewriter = LineRewriter::new(
+            context,
+            vec![Arc::new(replace_host)],
+            Charset::Declared(UTF_8),
+        );
+
+        // minified, so there's not a single newline
+        let link = "<a href=\"https://www.acme.com/p\">x</a>";
+        let body = link.repeat(MAX_PENDING * 4 / link.len());
+
+        let mut output = vec![];
+        let mut sent_early = false;
+        for chunk in body.as_bytes().chunks(8 * 1024) {
+            let next = rewriter.write(chunk);
+            sent_early = sent_early || !next.is_empty();
+            output.extend_from_slice(&next);
+            assert!(rewriter.pending.len() <= MAX_PENDING + 8 * 1024);
+        }
+        output.extend_from_slice(&rewriter.finish());
+
+        assert!(sent_early);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            body.replace("https://www.acme.com", "https://127.0.0.1:8080")
+        );
+    }
+
+    #[test]
+    fn test_safe_split() {
+        assert_eq!(safe_split(b"<p>a https://www.acme.com"), 3);
+        assert_eq!(safe_split(b"{\"url\":\"https://www.acme.com"), 8);
+        let blob = vec![b'a'; PENDING_TAIL * 2];
+        assert_eq!(safe_split(&blob), PENDING_TAIL);
+    }
+}
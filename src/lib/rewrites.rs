@@ -1,5 +1,10 @@
+use base64;
+use regex;
 use regex::Captures;
 use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use upstreams::ExtraUpstream;
 use url::Url;
 
 ///
@@ -22,6 +27,20 @@ pub struct RewriteContext {
     pub host_to_replace: String,
     pub target_host: String,
     pub target_port: u16,
+    ///
+    /// When set, rewritten URLs are given this scheme,
+    /// otherwise the original one is kept
+    ///
+    pub target_scheme: String,
+    ///
+    /// Extra hosts whose URLs are pointed at their local prefix
+    ///
+    pub upstreams: Vec<ExtraUpstream>,
+    ///
+    /// Shared by every response on a worker, see [Matchers]
+    ///
+    #[serde(skip)]
+    matchers: Arc<Matchers>,
 }
 
 impl RewriteContext {
@@ -36,15 +55,185 @@ impl RewriteContext {
         self.target_port = port;
         self
     }
+    pub fn with_scheme(mut self, scheme: impl Into<String>) -> RewriteContext {
+        self.target_scheme = scheme.into();
+        self
+    }
+    pub fn with_upstreams(mut self, upstreams: Vec<ExtraUpstream>) -> RewriteContext {
+        self.upstreams = upstreams;
+        self
+    }
+    pub fn with_matchers(mut self, matchers: Arc<Matchers>) -> RewriteContext {
+        self.matchers = matchers;
+        self
+    }
+    ///
+    /// The host to replace without any port, as
+    /// needed for things like cookie domains
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use bs::rewrites::*;
+    ///
+    /// assert_eq!(RewriteContext::new("localhost:8080").host_name(), "localhost");
+    /// assert_eq!(RewriteContext::new("www.acme.com").host_name(), "www.acme.com");
+    /// ```
+    ///
+    pub fn host_name(&self) -> &str {
+        match self.host_to_replace.rfind(':') {
+            Some(index)
+                if self.host_to_replace[index + 1..]
+                    .chars()
+                    .all(|c| c.is_digit(10)) =>
+            {
+                &self.host_to_replace[..index]
+            }
+            _ => &self.host_to_replace,
+        }
+    }
+}
+
+///
+/// Regexes are compiled the first time they're needed & then kept,
+/// rather than compiled again for every response.
+///
+/// Each worker has its own, kept on its AppState
+///
+#[derive(Default, Debug)]
+pub struct Matchers {
+    cache: Mutex<MatcherCache>,
+}
+
+///
+/// How many hosts [Matchers::header_urls] keeps matchers for
+///
+const MAX_HEADER_HOSTS: usize = 32;
+
+#[derive(Default, Debug)]
+struct MatcherCache {
+    hosts: HashMap<String, Arc<HostMatchers>>,
+    header_urls: HashMap<String, Arc<Regex>>,
+    base64_params: Option<Arc<Regex>>,
+}
+
+impl Matchers {
+    fn host(&self, host: &str) -> Arc<HostMatchers> {
+        self.cache
+            .lock()
+            .expect("should lock & unwrap matchers")
+            .hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(HostMatchers::new(host)))
+            .clone()
+    }
+
+    ///
+    /// Finds URLs for `host` within a header value, with or without
+    /// a scheme. The host must end where the match does, so that
+    /// `acme.com` never matches `acme.com.au`.
+    ///
+    /// When `host` has no port, one can still be given explicitly
+    /// (as in `https://acme.com:443`) - it's captured as `port`, for
+    /// the caller to check against the scheme
+    ///
+    pub fn header_urls(&self, host: &str) -> Arc<Regex> {
+        let mut cache = self.cache.lock().expect("should lock & unwrap matchers");
+        // requests are matched on their `Host` header, which can be anything
+        if cache.header_urls.len() >= MAX_HEADER_HOSTS && !cache.header_urls.contains_key(host) {
+            cache.header_urls.clear();
+        }
+        cache
+            .header_urls
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let has_port = host.rfind(':').map_or(false, |index| {
+                    host[index + 1..].chars().all(|c| c.is_digit(10))
+                });
+                let matcher = format!(
+                    r"(?i)(?P<scheme>https?:)?//{}{}(?P<end>[^\w.\-:]|$)",
+                    regex::escape(host),
+                    if has_port { "" } else { r"(?P<port>:\d+)?" }
+                );
+                Arc::new(Regex::new(&matcher).expect("valid regex"))
+            })
+            .clone()
+    }
+    fn base64_params(&self) -> Arc<Regex> {
+        self.cache
+            .lock()
+            .expect("should lock & unwrap matchers")
+            .base64_params
+            .get_or_insert_with(|| Arc::new(Regex::new(BASE64_PARAMS).expect("valid regex")))
+            .clone()
+    }
+}
+
+///
+/// Every way of writing a URL for a single host
+///
+#[derive(Debug)]
+struct HostMatchers {
+    url: Regex,
+    ///
+    /// In the same order as [ENCODED_URLS]
+    ///
+    encoded: Vec<Regex>,
+}
+
+impl HostMatchers {
+    fn new(host: &str) -> HostMatchers {
+        let url = format!("https?:(?:\\\\)?/(?:\\\\)?/{}", regex::escape(host));
+        HostMatchers {
+            url: Regex::new(&url).expect("valid regex"),
+            encoded: ENCODED_URLS
+                .iter()
+                .map(|encoded| encoded.matcher(host))
+                .collect(),
+        }
+    }
+}
+
+///
+/// A single rewrite of a text body, applied line by line.
+///
+/// Plain functions are rewriters too, anything that needs
+/// configuration can implement this directly
+///
+pub trait Rewriter: Send + Sync {
+    fn rewrite(&self, input: &str, context: &RewriteContext) -> String;
+    ///
+    /// Anything that was held back from earlier lines. Called once the
+    /// body has ended, and before a line that can't be decoded is sent
+    /// on untouched, so that the output stays in order
+    ///
+    fn flush(&self, _context: &RewriteContext) -> String {
+        String::new()
+    }
+}
+
+impl<F> Rewriter for F
+where
+    F: Fn(&str, &RewriteContext) -> String + Send + Sync,
+{
+    fn rewrite(&self, input: &str, context: &RewriteContext) -> String {
+        self(input, context)
+    }
 }
 
 ///
-/// Replace the host name in a string
+/// Replace the host name in a string, along with the
+/// hosts of any extra upstreams.
+///
+/// As well as plain URLs, this covers URLs that are escaped within
+/// JSON, percent-encoded (as in `?referer=https%3A%2F%2Fwww.acme.com`),
+/// written with HTML entities and Magento's base64 `uenc` parameters
 ///
 /// # Examples
 ///
 /// ```rust
 /// use bs::rewrites::*;
+/// use bs::upstreams::ExtraUpstream;
 ///
 /// let bytes = "<a href=\"https://www.acme.com\">Home</a>";
 /// let expected = "<a href=\"https://127.0.0.1:8000\">Home</a>";
@@ -53,18 +242,213 @@ impl RewriteContext {
 ///     .with_target("127.0.0.1", 8000);
 ///
 /// assert_eq!(expected, replace_host(bytes, &opts));
+///
+/// let bytes = "<img src=\"https://media.acme.com/logo.svg\">";
+/// let expected = "<img src=\"https://127.0.0.1:8000/__bs/upstream/media/logo.svg\">";
+///
+/// let opts = opts.with_upstreams(vec![
+///     ExtraUpstream::new("media", "https://media.acme.com").unwrap()
+/// ]);
+///
+/// assert_eq!(expected, replace_host(bytes, &opts));
 /// ```
 ///
 pub fn replace_host(bytes: &str, context: &RewriteContext) -> String {
-    let matcher = format!("https?:(?:\\\\)?/(?:\\\\)?/{}", context.host_to_replace);
-    Regex::new(&matcher)
-        .unwrap()
+    let replaced = replace_hosts(bytes, context);
+    replace_base64_params(&replaced, context)
+}
+
+fn replace_hosts(bytes: &str, context: &RewriteContext) -> String {
+    // extra upstreams go first, since they are often
+    // subdomains of the main host
+    let replaced = context
+        .upstreams
+        .iter()
+        .fold(bytes.to_string(), |acc, upstream| {
+            replace_urls(&acc, &upstream.host, &upstream.prefix(), context)
+        });
+    replace_urls(&replaced, &context.host_to_replace, "", context)
+}
+
+///
+/// Point every URL for `host` at the local server, with `prefix`
+/// added before the original path
+///
+fn replace_urls(bytes: &str, host: &str, prefix: &str, context: &RewriteContext) -> String {
+    let matchers = context.matchers.host(host);
+    let replaced = matchers
+        .url
         .replace_all(bytes, |item: &Captures| {
-            modify_url(item, &context).unwrap_or(String::from(""))
+            modify_url(item, &context)
+                .map(|url| format!("{}{}", url, prefix))
+                .unwrap_or(String::from(""))
+        })
+        .to_string();
+
+    ENCODED_URLS
+        .iter()
+        .zip(matchers.encoded.iter())
+        .filter(|(encoded, _)| encoded.might_match(&replaced))
+        .fold(replaced.clone(), |acc, (_, matcher)| {
+            EncodedUrl::replace(matcher, &acc, host, prefix, context)
+        })
+}
+
+///
+/// A way of writing the `:` and `/` of a URL, other than
+/// the plain and JSON escaped forms that [modify_url] handles
+///
+struct EncodedUrl {
+    ///
+    /// At least one of these must be present for the
+    /// (more expensive) regex to be tried
+    ///
+    markers: &'static [&'static str],
+    colon: &'static str,
+    slash: &'static str,
+}
+
+const ENCODED_URLS: [EncodedUrl; 4] = [
+    // JSON within JSON, `https:\\\/\\\/`
+    EncodedUrl {
+        markers: &["\\\\"],
+        colon: ":",
+        slash: "\\\\{2,}/",
+    },
+    EncodedUrl {
+        markers: &["%3A", "%3a"],
+        colon: "%3[aA]",
+        slash: "%2[fF]",
+    },
+    EncodedUrl {
+        markers: &["%253A", "%253a"],
+        colon: "%253[aA]",
+        slash: "%252[fF]",
+    },
+    EncodedUrl {
+        markers: &["&#", "&sol;"],
+        colon: ":|&#58;|&#x3[aA];|&colon;",
+        slash: "&#x2[fF];|&#47;|&sol;",
+    },
+];
+
+impl EncodedUrl {
+    fn might_match(&self, bytes: &str) -> bool {
+        self.markers.iter().any(|marker| bytes.contains(marker))
+    }
+
+    fn matcher(&self, host: &str) -> Regex {
+        let host_matcher = regex::escape(host).replace(':', &format!("(?:{})", self.colon));
+        let matcher = format!(
+            "(?P<scheme>https?)(?P<colon>{colon})(?P<slash>{slash})(?:{slash}){host}",
+            colon = self.colon,
+            slash = self.slash,
+            host = host_matcher
+        );
+        Regex::new(&matcher).expect("valid regex")
+    }
+
+    ///
+    /// The local URL is written with the same `:` and `/`
+    /// as the one it replaces
+    ///
+    fn replace(
+        matcher: &Regex,
+        bytes: &str,
+        host: &str,
+        prefix: &str,
+        context: &RewriteContext,
+    ) -> String {
+        matcher
+            .replace_all(bytes, |item: &Captures| {
+                let original = format!("{}://{}", &item["scheme"], host);
+                match local_url(&original, context) {
+                    Some(url) => {
+                        let url = format!("{}{}", url, prefix);
+                        let rest = url.splitn(2, "://").nth(1).unwrap_or("");
+                        format!(
+                            "{}{}{}{}{}",
+                            url.splitn(2, "://").next().unwrap_or(""),
+                            &item["colon"],
+                            &item["slash"],
+                            &item["slash"],
+                            rest.replace(':', &item["colon"])
+                                .replace('/', &item["slash"])
+                        )
+                    }
+                    None => item[0].to_string(),
+                }
+            })
+            .to_string()
+    }
+}
+
+const BASE64_PARAMS: &str = r#"(?P<key>(?:uenc|referer)(?:/|=|%3[dD]|\\?"\s*:\s*\\?"|&quot;:&quot;|&#34;:&#34;))(?P<value>[A-Za-z0-9_,\-]{8,})"#;
+
+///
+/// Rewrite URLs within Magento's base64 encoded parameters (`uenc`, and
+/// `referer` on login links), which are used to redirect after an action.
+///
+/// # Examples
+///
+/// ```rust
+/// use bs::rewrites::*;
+///
+/// let opts = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+///
+/// // https://www.acme.com/checkout/cart/ -> https://127.0.0.1:8080/checkout/cart/
+/// assert_eq!(
+///     replace_host("/wishlist/index/add/uenc/aHR0cHM6Ly93d3cuYWNtZS5jb20vY2hlY2tvdXQvY2FydC8,/", &opts),
+///     "/wishlist/index/add/uenc/aHR0cHM6Ly8xMjcuMC4wLjE6ODA4MC9jaGVja291dC9jYXJ0Lw,,/"
+/// );
+/// ```
+///
+fn replace_base64_params(bytes: &str, context: &RewriteContext) -> String {
+    if !bytes.contains("uenc") && !bytes.contains("referer") {
+        return bytes.to_string();
+    }
+    context
+        .matchers
+        .base64_params()
+        .replace_all(bytes, |item: &Captures| {
+            let value = rewrite_base64_url(&item["value"], context)
+                .unwrap_or_else(|| item["value"].to_string());
+            format!("{}{}", &item["key"], value)
         })
         .to_string()
 }
 
+///
+/// Magento's URL encoder swaps the `+/=` of base64 for `-_,` so that
+/// the value is safe within a path. `None` when there's nothing to rewrite
+///
+fn rewrite_base64_url(value: &str, context: &RewriteContext) -> Option<String> {
+    let standard: String = value
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            ',' => '=',
+            c => c,
+        })
+        .collect();
+    let decoded = String::from_utf8(base64::decode(&standard).ok()?).ok()?;
+    let rewritten = replace_hosts(&decoded, context);
+    if rewritten == decoded {
+        return None;
+    }
+    let encoded = base64::encode(&rewritten)
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            '=' => ',',
+            c => c,
+        })
+        .collect();
+    Some(encoded)
+}
+
 ///
 /// Attempt to modify a URL,
 ///
@@ -75,8 +459,18 @@ pub fn replace_host(bytes: &str, context: &RewriteContext) -> String {
 pub fn modify_url(caps: &Captures, context: &RewriteContext) -> Option<String> {
     let first_match = caps.iter().nth(0)?;
     let match_item = first_match?;
-    let mut url = Url::parse(match_item.as_str()).ok()?;
+    local_url(match_item.as_str(), context)
+}
+
+///
+/// Swap the scheme, host & port of a URL (with no path) for the local ones
+///
+fn local_url(input: &str, context: &RewriteContext) -> Option<String> {
+    let mut url = Url::parse(input).ok()?;
 
+    if !context.target_scheme.is_empty() {
+        url.set_scheme(&context.target_scheme).ok()?;
+    }
     url.set_host(Some(&context.target_host)).ok()?;
     url.set_port(Some(context.target_port)).ok()?;
     let mut as_string = url.to_string();
@@ -102,11 +496,27 @@ mod tests {
             host_to_replace: String::from("www.acme.com"),
             target_host: String::from("127.0.0.1"),
             target_port: 8080,
+            ..RewriteContext::default()
         };
         let actual = replace_host(bytes, &context);
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_rewrites_with_port() {
+        let bytes = "
+    <a href=\"http://localhost:8081/uk/\">Home</a>
+    <a href=\"http://localhost:8080/uk/\">Home</a>
+    ";
+        let expected = "
+    <a href=\"http://localhost:8081/uk/\">Home</a>
+    <a href=\"http://127.0.0.1:9000/uk/\">Home</a>
+    ";
+        let context = RewriteContext::new("localhost:8080").with_target("127.0.0.1", 9000);
+        let actual = replace_host(bytes, &context);
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn test_rewrites_within_escaped_json() {
         let bytes = r#"
@@ -119,6 +529,7 @@ mod tests {
             host_to_replace: String::from("www.acme.com"),
             target_host: String::from("127.0.0.1"),
             target_port: 8080,
+            ..RewriteContext::default()
         };
         let actual = replace_host(bytes, &context);
         println!("actual={}", actual);
@@ -145,9 +556,123 @@ mod tests {
             host_to_replace: String::from("www.acme.com"),
             target_host: String::from("127.0.0.1"),
             target_port: 8080,
+            ..RewriteContext::default()
         };
         let actual = replace_host(bytes, &context);
         println!("actual={}", actual);
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_rewrites_upstreams() {
+        let bytes =
+            r#"<link href="http://static.acme.com/app.css"><a href="https://www.acme.com/">"#;
+        let expected = r#"<link href="https://127.0.0.1:8080/__bs/upstream/static/app.css"><a href="https://127.0.0.1:8080/">"#;
+        let context = RewriteContext::new("www.acme.com")
+            .with_target("127.0.0.1", 8080)
+            .with_scheme("https")
+            .with_upstreams(vec![
+                ExtraUpstream::new("static", "http://static.acme.com").unwrap()
+            ]);
+        assert_eq!(replace_host(bytes, &context), expected);
+    }
+
+    #[test]
+    fn test_rewrites_encoded_urls() {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let cases = vec![
+            (
+                "/customer/account/login/?referer=https%3A%2F%2Fwww.acme.com%2Fcheckout%2F",
+                "/customer/account/login/?referer=https%3A%2F%2F127.0.0.1%3A8080%2Fcheckout%2F",
+            ),
+            (
+                "?return=https%253a%252f%252fwww.acme.com%252f",
+                "?return=https%253a%252f%252f127.0.0.1%253a8080%252f",
+            ),
+            (
+                "<a href=\"https:&#x2F;&#x2F;www.acme.com&#x2F;sale\">",
+                "<a href=\"https:&#x2F;&#x2F;127.0.0.1:8080&#x2F;sale\">",
+            ),
+            (
+                r#"{"json": "{\"url\": \"https:\\\/\\\/www.acme.com\\\/\"}"}"#,
+                r#"{"json": "{\"url\": \"https:\\\/\\\/127.0.0.1:8080\\\/\"}"}"#,
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(replace_host(input, &context), expected);
+        }
+    }
+
+    #[test]
+    fn test_rewrites_to_ipv6_and_lan_hosts() {
+        let bytes = "<a href=\"https://www.acme.com/checkout\">Checkout</a>";
+        let context = RewriteContext::new("www.acme.com").with_target("[fe80::1]", 8080);
+        assert_eq!(
+            replace_host(bytes, &context),
+            "<a href=\"https://[fe80::1]:8080/checkout\">Checkout</a>"
+        );
+        let context = RewriteContext::new("www.acme.com").with_target("192.168.0.10", 8080);
+        assert_eq!(
+            replace_host(bytes, &context),
+            "<a href=\"https://192.168.0.10:8080/checkout\">Checkout</a>"
+        );
+    }
+
+    #[test]
+    fn test_rewrites_encoded_upstream_urls() {
+        let bytes = "?img=https%3A%2F%2Fmedia.acme.com%2Flogo.png";
+        let expected = "?img=http%3A%2F%2F127.0.0.1%3A8080%2F__bs%2Fupstream%2Fmedia%2Flogo.png";
+        let context = RewriteContext::new("www.acme.com")
+            .with_target("127.0.0.1", 8080)
+            .with_scheme("http")
+            .with_upstreams(vec![
+                ExtraUpstream::new("media", "https://media.acme.com").unwrap()
+            ]);
+        assert_eq!(replace_host(bytes, &context), expected);
+    }
+
+    #[test]
+    fn test_rewrites_uenc_params() {
+        let context = RewriteContext::new("www.acme.com").with_target("127.0.0.1", 8080);
+        let data_post = r#"data-post='{"action":"https:\/\/www.acme.com\/checkout\/cart\/add\/","data":{"product":"1","uenc":"aHR0cHM6Ly93d3cuYWNtZS5jb20vY2hlY2tvdXQvY2FydC8,"}}'"#;
+        let expected = r#"data-post='{"action":"https://127.0.0.1:8080\/checkout\/cart\/add\/","data":{"product":"1","uenc":"aHR0cHM6Ly8xMjcuMC4wLjE6ODA4MC9jaGVja291dC9jYXJ0Lw,,"}}'"#;
+        assert_eq!(replace_host(data_post, &context), expected);
+
+        let entities =
+            "&quot;uenc&quot;:&quot;aHR0cHM6Ly93d3cuYWNtZS5jb20vY2hlY2tvdXQvY2FydC8,&quot;";
+        let expected =
+            "&quot;uenc&quot;:&quot;aHR0cHM6Ly8xMjcuMC4wLjE6ODA4MC9jaGVja291dC9jYXJ0Lw,,&quot;";
+        assert_eq!(replace_host(entities, &context), expected);
+
+        // values that aren't base64 URLs for the target are left alone
+        let other = "/uenc/aHR0cHM6Ly9vdGhlci5jb20v/ ?referer=notbase64!";
+        assert_eq!(replace_host(other, &context), other);
+    }
+
+    #[test]
+    fn test_matchers_are_reused() {
+        let matchers = Arc::new(Matchers::default());
+        let context = RewriteContext::new("www.acme.com")
+            .with_target("127.0.0.1", 8080)
+            .with_upstreams(vec![
+                ExtraUpstream::new("media", "https://media.acme.com").unwrap()
+            ])
+            .with_matchers(matchers.clone());
+        let first = context.matchers.host("www.acme.com");
+        replace_host(
+            "https://www.acme.com/?uenc=aHR0cHM6Ly93d3cuYWNtZS5jb20v",
+            &context,
+        );
+        replace_host("https://media.acme.com/logo.svg", &context);
+
+        assert!(Arc::ptr_eq(&first, &context.matchers.host("www.acme.com")));
+        assert_eq!(matchers.cache.lock().unwrap().hosts.len(), 2);
+
+        // the next response on the same worker
+        let next = RewriteContext::new("www.acme.com")
+            .with_target("127.0.0.1", 8080)
+            .with_matchers(matchers.clone());
+        replace_host("https://www.acme.com/", &next);
+        assert!(Arc::ptr_eq(&first, &next.matchers.host("www.acme.com")));
+    }
 }
@@ -1,19 +1,20 @@
+use actix_web::http::Method;
 use actix_web::App;
 use app_state::AppState;
 use config::ProgramConfig;
 use config::ProgramStartError;
-use from_file::FromFile;
+use disk_cache::handle_cache_invalidate;
+use har::handle_har;
 use options::ProgramOptions;
+use overrides::add_overrides;
 use preset::Preset;
 use preset::PresetOptions;
 use presets::m2::preset_m2::M2Preset;
 use presets::m2::preset_m2_opts::M2PresetOptions;
-use presets::m2::seed::SeedData;
 use proxy_transform::proxy_transform;
-use rjs::RequireJsClientConfig;
+use shared_state::SharedState;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::Mutex;
+use upstream_error::handle_errors_json;
 
 pub type PresetsMap = HashMap<usize, Box<Preset<AppState>>>;
 
@@ -28,13 +29,25 @@ pub fn apply_presets(
         app = subject_preset.add_before_middleware(app);
     }
 
+    // overrides come before anything the presets add, so that
+    // a patched requirejs-config.js (for example) is what gets served
+    app = add_overrides(app, &program_config.overrides);
+
     // enhances
     for (index, _) in program_config.presets.iter().enumerate() {
         let subject_preset = presets_map.get(&index).expect("Missing preset");
         app = subject_preset.enhance(app);
     }
 
-    app.default_resource(|r| r.f(proxy_transform))
+    app.resource("/__bs/errors.json", |r| {
+        r.method(Method::GET).f(handle_errors_json)
+    })
+    .resource("/__bs/har", |r| r.method(Method::GET).f(handle_har))
+    .resource("/__bs/cache", |r| {
+        r.method(Method::DELETE).f(handle_cache_invalidate);
+        r.method(Method::POST).f(handle_cache_invalidate);
+    })
+    .default_resource(|r| r.f(proxy_transform))
 }
 
 ///
@@ -91,7 +104,7 @@ pub fn validate_presets(program_config: &ProgramConfig) -> Result<(), ProgramSta
 pub fn state_and_presets(
     opts: &ProgramOptions,
     program_config: &ProgramConfig,
-    maybe_seed: &Option<String>,
+    shared: &SharedState,
 ) -> (AppState, PresetsMap) {
     //
     // Use a HashMap + index lookup for anything
@@ -121,42 +134,14 @@ pub fn state_and_presets(
         }
     }
 
-    let mut app_state = create_state(maybe_seed.clone(), program_config.clone(), opts.clone());
+    let mut app_state = AppState::with_shared(shared, program_config.clone(), opts.clone());
 
-    // Add rewrites phase
+    // Add rewrites & snippets phase
     for (index, _) in program_config.presets.iter().enumerate() {
         let subject_preset = presets_map.get(&index).expect("Missing preset");
         app_state.rewrites.extend(subject_preset.rewrites());
+        app_state.snippets.extend(subject_preset.snippets());
     }
 
     (app_state, presets_map)
 }
-
-///
-/// Build up the application state based on a potential
-/// incoming seed
-///
-pub fn create_state(
-    maybe_seed: Option<String>,
-    program_config: ProgramConfig,
-    opts: ProgramOptions,
-) -> AppState {
-    let (req_log, rjs_client_config) = match maybe_seed {
-        Some(ref s) => match SeedData::from_file(&s) {
-            Ok(seed) => (seed.req_log, seed.rjs_client_config),
-            Err(e) => {
-                eprintln!("Could not read seed, {:?}", e);
-                (vec![], RequireJsClientConfig::default())
-            }
-        },
-        None => (vec![], RequireJsClientConfig::default()),
-    };
-
-    AppState {
-        program_config,
-        opts,
-        rewrites: vec![],
-        req_log: Mutex::new(req_log),
-        rjs_client_config: Arc::new(Mutex::new(rjs_client_config)),
-    }
-}
@@ -0,0 +1,73 @@
+use disk_cache::CacheStore;
+use from_file::FromFile;
+use har::HarRecorder;
+use presets::m2::seed::SeedData;
+use rjs::modules::ModuleData;
+use rjs::RequireJsClientConfig;
+use std::sync::{Arc, Mutex};
+use upstream_error::UpstreamFailure;
+
+///
+/// Everything that's captured while browsing. It's created once
+/// and given to every worker, so it doesn't matter which of them
+/// served a request - `/__bs/build.json` always sees all of them
+///
+#[derive(Clone, Default)]
+pub struct SharedState {
+    pub req_log: Arc<Mutex<Vec<ModuleData>>>,
+    pub rjs_client_config: Arc<Mutex<RequireJsClientConfig>>,
+    pub upstream_errors: Arc<Mutex<Vec<UpstreamFailure>>>,
+    pub har: Arc<HarRecorder>,
+    pub cache: Option<CacheStore>,
+}
+
+impl SharedState {
+    ///
+    /// Start from a previous session's `/__bs/seed.json`, when given
+    ///
+    pub fn from_seed(maybe_seed: &Option<String>) -> SharedState {
+        let (req_log, rjs_client_config) = match *maybe_seed {
+            Some(ref s) => match SeedData::from_file(&s) {
+                Ok(seed) => (seed.req_log, seed.rjs_client_config),
+                Err(e) => {
+                    eprintln!("Could not read seed, {:?}", e);
+                    (vec![], RequireJsClientConfig::default())
+                }
+            },
+            None => (vec![], RequireJsClientConfig::default()),
+        };
+
+        SharedState {
+            req_log: Arc::new(Mutex::new(req_log)),
+            rjs_client_config: Arc::new(Mutex::new(rjs_client_config)),
+            ..SharedState::default()
+        }
+    }
+    pub fn with_har(mut self, har: HarRecorder) -> SharedState {
+        self.har = Arc::new(har);
+        self
+    }
+    pub fn with_cache(mut self, cache: CacheStore) -> SharedState {
+        self.cache = Some(cache);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_seed() {
+        let shared = SharedState::from_seed(&Some("test/fixtures/seed.json".to_string()));
+        let worker = shared.clone();
+        assert!(worker.req_log.lock().unwrap().len() > 0);
+
+        // every worker sees what any of the others captured
+        worker.req_log.lock().unwrap().clear();
+        assert_eq!(shared.req_log.lock().unwrap().len(), 0);
+
+        let shared = SharedState::from_seed(&None);
+        assert_eq!(shared.req_log.lock().unwrap().len(), 0);
+    }
+}
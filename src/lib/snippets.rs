@@ -0,0 +1,469 @@
+use glob::Glob;
+use regex::Regex;
+use rewrites::{RewriteContext, Rewriter};
+use serde::de::{self, Deserialize, Deserializer};
+use std::fs;
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+///
+/// Markup that's added to every proxied HTML page, either
+/// given inline with `html` or read from a `file`.
+///
+/// Files are read for every page, so they can be edited
+/// without restarting. An empty `paths` list matches every page
+///
+/// # Examples
+///
+/// ```yaml
+/// snippets:
+///   - position: body_start
+///     html: <div class="bs-banner">You are on config-gen</div>
+///   - position: body_end
+///     file: ./snippets/report-errors.html
+///     paths: ["/checkout/*"]
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    pub position: SnippetPosition,
+    pub source: SnippetSource,
+    pub paths: Vec<Glob>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnippetPosition {
+    ///
+    /// Just before `</head>`
+    ///
+    HeadEnd,
+    ///
+    /// Just after the opening `<body>` tag
+    ///
+    BodyStart,
+    ///
+    /// Just before `</body>`
+    ///
+    BodyEnd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnippetSource {
+    Html(String),
+    File(PathBuf),
+}
+
+#[derive(Deserialize)]
+struct RawSnippet {
+    position: SnippetPosition,
+    html: Option<String>,
+    file: Option<String>,
+    #[serde(default)]
+    paths: Vec<Glob>,
+}
+
+impl<'de> Deserialize<'de> for Snippet {
+    fn deserialize<D>(deserializer: D) -> Result<Snippet, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawSnippet::deserialize(deserializer)?;
+        let source = match (raw.html, raw.file) {
+            (Some(html), None) => SnippetSource::Html(html),
+            (None, Some(file)) => SnippetSource::File(PathBuf::from(file)),
+            _ => {
+                return Err(de::Error::custom(
+                    "a snippet needs either an `html` string or a `file`",
+                ))
+            }
+        };
+        Ok(Snippet {
+            position: raw.position,
+            source,
+            paths: raw.paths,
+        })
+    }
+}
+
+impl Snippet {
+    ///
+    /// An inline snippet for every page, as added by presets
+    ///
+    pub fn html(position: SnippetPosition, html: impl Into<String>) -> Snippet {
+        Snippet {
+            position,
+            source: SnippetSource::Html(html.into()),
+            paths: vec![],
+        }
+    }
+    pub fn matches(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|g| g.matches(path))
+    }
+    fn content(&self) -> Option<String> {
+        match self.source {
+            SnippetSource::Html(ref html) => Some(html.clone()),
+            SnippetSource::File(ref file) => match fs::read_to_string(file) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    error!("could not read the snippet `{}`, {}", file.display(), e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+///
+/// Adds snippets to a single page as it streams through.
+///
+/// `head_end` & `body_start` go in at the first occurrence of
+/// their tag, `body_end` at the last - so everything from a `</body>`
+/// onwards is held back until either the page ends, another one is seen
+/// or a line that can't be decoded follows it. Tags within a `<script>` are skipped, since inline scripts &
+/// templates often contain them as strings
+///
+pub struct SnippetInjector {
+    head_end: Injection,
+    body_start: Injection,
+    body_end: Injection,
+    body_tag: Regex,
+    partial_body_tag: Regex,
+    held: Mutex<String>,
+    in_script: AtomicBool,
+}
+
+///
+/// After this much, a `</body>` can't be the end of the page, and
+/// a `<body` can't be a tag, so they're no longer held back
+///
+const MAX_HELD: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Injection {
+    html: String,
+    done: AtomicBool,
+}
+
+impl Injection {
+    fn pending(&self) -> bool {
+        !self.html.is_empty() && !self.done.load(Ordering::SeqCst)
+    }
+    fn insert(&self, output: &mut String, index: usize) {
+        output.insert_str(index, &self.html);
+        self.done.store(true, Ordering::SeqCst);
+    }
+}
+
+impl SnippetInjector {
+    ///
+    /// `None` when no snippets apply to the path
+    ///
+    pub fn new(snippets: &[Snippet], path: &str) -> Option<SnippetInjector> {
+        let mut injector = SnippetInjector {
+            head_end: Injection::default(),
+            body_start: Injection::default(),
+            body_end: Injection::default(),
+            body_tag: Regex::new(r"(?i)<body\b[^>]*>").expect("valid regex"),
+            partial_body_tag: Regex::new(r"(?i)<body\b[^>]*$").expect("valid regex"),
+            held: Mutex::new(String::new()),
+            in_script: AtomicBool::new(false),
+        };
+
+        for snippet in snippets.iter().filter(|s| s.matches(path)) {
+            if let Some(content) = snippet.content() {
+                let injection = match snippet.position {
+                    SnippetPosition::HeadEnd => &mut injector.head_end,
+                    SnippetPosition::BodyStart => &mut injector.body_start,
+                    SnippetPosition::BodyEnd => &mut injector.body_end,
+                };
+                injection.html.push_str(&content);
+            }
+        }
+
+        let empty = injector.head_end.html.is_empty()
+            && injector.body_start.html.is_empty()
+            && injector.body_end.html.is_empty();
+
+        match empty {
+            true => None,
+            false => Some(injector),
+        }
+    }
+    ///
+    /// Where the part that's held back starts, if any - a `<body`
+    /// tag that's not been closed yet, or the last `</body>`
+    ///
+    fn hold_from(
+        &self,
+        output: &str,
+        lower: &str,
+        outside: &[Range<usize>],
+        body_tag_found: bool,
+    ) -> Option<usize> {
+        let partial_body_tag = match self.body_start.pending() && !body_tag_found {
+            true => outside
+                .last()
+                .filter(|range| range.end == output.len())
+                .and_then(|range| {
+                    self.partial_body_tag
+                        .find(&output[range.clone()])
+                        .map(|m| range.start + m.start())
+                }),
+            false => None,
+        };
+        let body_end = match self.body_end.pending() {
+            true => rfind_outside(lower, outside, "</body>"),
+            false => None,
+        };
+        let from = match (partial_body_tag, body_end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        from.filter(|from| output.len() - from <= MAX_HELD)
+    }
+}
+
+///
+/// # Examples
+///
+/// ```
+/// use bs::rewrites::{RewriteContext, Rewriter};
+/// use bs::snippets::{Snippet, SnippetInjector, SnippetPosition};
+///
+/// let snippets = vec![
+///     Snippet::html(SnippetPosition::HeadEnd, "<style>.bs{}</style>"),
+///     Snippet::html(SnippetPosition::BodyStart, "<div class=\"bs\"></div>"),
+///     Snippet::html(SnippetPosition::BodyEnd, "<script src=\"/bs.js\"></script>"),
+/// ];
+/// let injector = SnippetInjector::new(&snippets, "/").unwrap();
+/// let context = RewriteContext::default();
+///
+/// assert_eq!(
+///     injector.rewrite("<head></head><BODY class=\"cms-home\">", &context),
+///     "<head><style>.bs{}</style></head><BODY class=\"cms-home\"><div class=\"bs\"></div>"
+/// );
+///
+/// // only the first occurrence is used
+/// assert_eq!(injector.rewrite("<body>", &context), "<body>");
+///
+/// // but `</body>` waits for the end of the page
+/// assert_eq!(injector.rewrite("</body></html>", &context), "");
+/// assert_eq!(
+///     injector.flush(&context),
+///     "<script src=\"/bs.js\"></script></body></html>"
+/// );
+/// ```
+///
+impl Rewriter for SnippetInjector {
+    fn rewrite(&self, input: &str, _context: &RewriteContext) -> String {
+        let mut held = self.held.lock().expect("should lock & unwrap held");
+        let mut output = mem::replace(&mut *held, String::new());
+        output.push_str(input);
+
+        // whatever was held started outside of a script
+        let lower = output.to_ascii_lowercase();
+        let (outside, in_script) = outside_scripts(&lower, self.in_script.load(Ordering::SeqCst));
+
+        let head_end = match self.head_end.pending() {
+            true => find_outside(&lower, &outside, "</head>"),
+            false => None,
+        };
+        let body_start = match self.body_start.pending() {
+            true => outside
+                .iter()
+                .filter_map(|range| {
+                    self.body_tag
+                        .find(&output[range.clone()])
+                        .map(|m| range.start + m.end())
+                })
+                .next(),
+            false => None,
+        };
+        let mut hold_from = self.hold_from(&output, &lower, &outside, body_start.is_some());
+
+        // from the back, so that each index is still valid
+        let mut inserts = vec![];
+        inserts.extend(head_end.map(|index| (index, &self.head_end)));
+        inserts.extend(body_start.map(|index| (index, &self.body_start)));
+        inserts.sort_by_key(|(index, _)| *index);
+        for (index, injection) in inserts.into_iter().rev() {
+            let len = injection.html.len();
+            injection.insert(&mut output, index);
+            hold_from = hold_from.map(|from| match index <= from {
+                true => from + len,
+                false => from,
+            });
+        }
+
+        match hold_from {
+            Some(from) => {
+                *held = output.split_off(from);
+                self.in_script.store(false, Ordering::SeqCst);
+            }
+            None => self.in_script.store(in_script, Ordering::SeqCst),
+        }
+
+        output
+    }
+    fn flush(&self, _context: &RewriteContext) -> String {
+        let mut output = mem::replace(
+            &mut *self.held.lock().expect("should lock & unwrap held"),
+            String::new(),
+        );
+        let lower = output.to_ascii_lowercase();
+        let (outside, in_script) = outside_scripts(&lower, false);
+        if self.body_end.pending() {
+            if let Some(index) = rfind_outside(&lower, &outside, "</body>") {
+                self.body_end.insert(&mut output, index);
+            }
+        }
+        self.in_script.store(in_script, Ordering::SeqCst);
+        output
+    }
+}
+
+///
+/// The parts of a (lowercased) page that aren't within a `<script>`, and
+/// whether it ends inside of one. Lowercasing only ASCII keeps byte
+/// offsets the same as the original string
+///
+fn outside_scripts(lower: &str, in_script: bool) -> (Vec<Range<usize>>, bool) {
+    let mut ranges = vec![];
+    let mut in_script = in_script;
+    let mut start = 0;
+    loop {
+        let (tag, next) = match in_script {
+            true => ("</script", lower[start..].find("</script")),
+            false => ("<script", lower[start..].find("<script")),
+        };
+        match next {
+            Some(index) => {
+                if !in_script {
+                    ranges.push(start..start + index);
+                }
+                start += index + tag.len();
+                in_script = !in_script;
+            }
+            None => {
+                if !in_script {
+                    ranges.push(start..lower.len());
+                }
+                return (ranges, in_script);
+            }
+        }
+    }
+}
+
+fn find_outside(lower: &str, outside: &[Range<usize>], tag: &str) -> Option<usize> {
+    outside
+        .iter()
+        .filter_map(|range| lower[range.clone()].find(tag).map(|i| range.start + i))
+        .next()
+}
+
+fn rfind_outside(lower: &str, outside: &[Range<usize>], tag: &str) -> Option<usize> {
+    outside
+        .iter()
+        .rev()
+        .filter_map(|range| lower[range.clone()].rfind(tag).map(|i| range.start + i))
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_parse_snippets() {
+        let i = r#"
+- position: head_end
+  html: <script src="/__bs/errors.js"></script>
+- position: body_end
+  file: test/fixtures/override.js
+  paths: ["/checkout/*"]
+        "#;
+        let snippets: Vec<Snippet> = serde_yaml::from_str(i).unwrap();
+        assert_eq!(snippets[0].position, SnippetPosition::HeadEnd);
+        assert_eq!(
+            snippets[1].source,
+            SnippetSource::File("test/fixtures/override.js".into())
+        );
+        assert!(!snippets[1].matches("/"));
+
+        let invalid = serde_yaml::from_str::<Snippet>("{position: body_end}");
+        assert!(invalid.is_err());
+        let invalid = serde_yaml::from_str::<Snippet>("{position: footer, html: a}");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_injects_across_lines() {
+        let snippets = vec![
+            Snippet::html(SnippetPosition::BodyStart, "<b>start</b>"),
+            Snippet::html(SnippetPosition::BodyEnd, "<b>end</b>"),
+        ];
+        let injector = SnippetInjector::new(&snippets, "/").unwrap();
+        let context = RewriteContext::default();
+
+        let lines = vec!["<body\n", "  class=\"x\">\n", "<p>hi</p>\n", "</Body>\n"];
+        let mut output: String = lines
+            .iter()
+            .map(|line| injector.rewrite(line, &context))
+            .collect();
+        output.push_str(&injector.flush(&context));
+
+        assert_eq!(
+            output,
+            "<body\n  class=\"x\"><b>start</b>\n<p>hi</p>\n<b>end</b></Body>\n"
+        );
+    }
+
+    #[test]
+    fn test_skips_tags_in_scripts() {
+        let snippets = vec![
+            Snippet::html(SnippetPosition::HeadEnd, "<b>head</b>"),
+            Snippet::html(SnippetPosition::BodyEnd, "<b>end</b>"),
+        ];
+        let injector = SnippetInjector::new(&snippets, "/").unwrap();
+        let context = RewriteContext::default();
+
+        let lines = vec![
+            "<head><script>\n",
+            "var t = '</head><body></body>';\n",
+            "</script></head>\n",
+            "<body><p>hi</p></body>\n",
+            "<script type=\"text/x-magento-template\"></body></script>\n",
+            "</body>\n",
+        ];
+        let mut output: String = lines
+            .iter()
+            .map(|line| injector.rewrite(line, &context))
+            .collect();
+        output.push_str(&injector.flush(&context));
+
+        assert_eq!(
+            output,
+            "<head><script>\n\
+             var t = '</head><body></body>';\n\
+             </script><b>head</b></head>\n\
+             <body><p>hi</p></body>\n\
+             <script type=\"text/x-magento-template\"></body></script>\n\
+             <b>end</b></body>\n"
+        );
+    }
+
+    #[test]
+    fn test_no_matching_snippets() {
+        let snippets = vec![Snippet {
+            paths: vec![Glob::new("/checkout/*").unwrap()],
+            ..Snippet::html(SnippetPosition::BodyEnd, "<b>end</b>")
+        }];
+        assert!(SnippetInjector::new(&snippets, "/").is_none());
+        assert!(SnippetInjector::new(&snippets, "/checkout/cart").is_some());
+    }
+}
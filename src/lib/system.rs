@@ -1,17 +1,29 @@
 use actix;
 use actix_web::{server, App};
 use config::{ProgramConfig, ProgramStartError};
+use connector::ssl_connector;
+use disk_cache::CacheStore;
 use from_file::FromFile;
+use har::HarRecorder;
 use options::ProgramOptions;
 use options::ProxyScheme;
+use options::UpstreamTls;
+use replay::start_replay_server;
 use setup::apply_presets;
 use setup::state_and_presets;
 use setup::validate_presets;
+use shared_state::SharedState;
 use ssl;
-use std::net::SocketAddr;
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
-pub fn create(opts: ProgramOptions) -> Result<(actix::SystemRunner, String), ProgramStartError> {
+///
+/// Start the proxy, giving back the URLs it can be reached on. The
+/// first one is always usable from this machine, when listening on
+/// every interface the others are for devices on the same network
+///
+pub fn create(
+    opts: ProgramOptions,
+) -> Result<(actix::SystemRunner, Vec<String>), ProgramStartError> {
     //
     // The underlying Actor System
     //
@@ -28,19 +40,40 @@ pub fn create(opts: ProgramOptions) -> Result<(actix::SystemRunner, String), Pro
     };
 
     //
-    // Clone server opts to be used in multi threads
+    // The upstream proxy can be given in either place,
+    // but the CLI flag takes precedence
     //
-    let server_opts = opts.clone();
+    let opts = match opts.upstream_proxy {
+        Some(..) => opts,
+        None => {
+            let upstream_proxy = program_config.upstream_proxy.clone();
+            opts.with_upstream_proxy(upstream_proxy)
+        }
+    };
 
     //
-    // The address that the server will be accessible on
+    // When replaying, the recording is served locally and every
+    // connection to the target goes there instead. Its certificate
+    // is self-signed, so can never be verified
     //
-    let local_addr = format!("127.0.0.1:{}", opts.port.clone());
+    let opts = match opts.replay.clone() {
+        Some(recording) => {
+            let replay_addr = start_replay_server(recording, &opts.scheme)?;
+            opts.with_replay_addr(Some(replay_addr))
+                .with_upstream_tls(UpstreamTls::default())
+        }
+        None => opts,
+    };
 
     //
-    // Did the user provide a seed?
+    // Clone server opts to be used in multi threads
     //
-    let maybe_seed = server_opts.seed_file.clone();
+    let server_opts = opts.clone();
+
+    //
+    // The addresses that the server will be accessible on
+    //
+    let bind_addrs = opts.bind_addrs();
 
     //
     // Exit early if any presets fail validation
@@ -48,49 +81,128 @@ pub fn create(opts: ProgramOptions) -> Result<(actix::SystemRunner, String), Pro
     let _validated_presets = validate_presets(&program_config)?;
 
     //
-    // Now start the server
+    // Exit early if the TLS options for the target are unusable,
+    // since each worker will need to create them
     //
-    let s = server::new(move || {
-        let (app_state, presets_map) = state_and_presets(&opts, &program_config, &maybe_seed);
-        let app = App::with_state(app_state);
-        apply_presets(app, &program_config, &presets_map)
-    })
-    .workers(1);
+    let _validated_tls = ssl_connector(&opts.upstream_tls)?;
 
     //
-    // Bind on either http or https depending on the
-    // target URL's scheme
+    // Captures (starting from the seed, if one was given) are
+    // shared by every worker, whichever one served the request
     //
-    let s = match server_opts.scheme {
-        ProxyScheme::Http => s.bind(&local_addr).map_err(ProgramStartError::BindHttp)?,
-        ProxyScheme::Https => {
-            let builder = ssl::builder()?;
-            s.bind_ssl(&local_addr, builder)
-                .map_err(ProgramStartError::BindHttps)?
+    let shared = SharedState::from_seed(&server_opts.seed_file);
+    let shared = match server_opts.har.file {
+        Some(ref file) => {
+            shared.with_har(HarRecorder::to_file(file).map_err(ProgramStartError::HarFile)?)
         }
+        None => shared,
+    };
+    let shared = match program_config.cache.enabled {
+        true => shared.with_cache(CacheStore::open(&program_config.cache)),
+        false => shared,
     };
 
     //
-    // Get the first address that was bound successfully
+    // Now start the server
     //
-    let addrs = s.addrs();
-    let first_addr = match addrs.get(0) {
-        Some(SocketAddr::V4(addr)) => Some(addr),
-        Some(SocketAddr::V6(..)) => None,
-        None => None,
-    };
+    let mut s = server::new(move || {
+        let (app_state, presets_map) = state_and_presets(&opts, &program_config, &shared);
+        let app = App::with_state(app_state);
+        apply_presets(app, &program_config, &presets_map)
+    })
+    .workers(server_opts.workers);
+
+    //
+    // Bind every address on either http or https
+    // depending on the target URL's scheme
+    //
+    for addr in bind_addrs.iter() {
+        s = match server_opts.scheme {
+            ProxyScheme::Http => s.bind(addr).map_err(ProgramStartError::BindHttp)?,
+            ProxyScheme::Https => {
+                let builder = ssl::builder()?;
+                s.bind_ssl(addr, builder)
+                    .map_err(ProgramStartError::BindHttps)?
+            }
+        };
+    }
 
     //
     // if there's not at LEAST 1 address, it's a program start error
     //
-    let addr: &SocketAddrV4 = first_addr.ok_or(ProgramStartError::Ip)?;
+    let addrs = s.addrs();
+    if addrs.is_empty() {
+        return Err(ProgramStartError::Ip);
+    }
 
     //
     // Start the server
     //
     s.shutdown_timeout(0).start();
 
-    let output_addr = format!("{}://{}:{}", server_opts.scheme, addr.ip(), addr.port());
+    let output_addrs = addrs.iter().flat_map(|addr| reachable_addrs(addr)).fold(
+        vec![],
+        |mut acc: Vec<String>, addr| {
+            let url = format!("{}://{}", server_opts.scheme, addr);
+            if !acc.contains(&url) {
+                acc.push(url);
+            }
+            acc
+        },
+    );
 
-    Ok((sys, output_addr))
+    Ok((sys, output_addrs))
+}
+
+///
+/// The addresses a bound one can be browsed to with - a wildcard
+/// one can't, so the loopback & (when known) LAN address are used
+///
+/// # Examples
+///
+/// ```
+/// use bs::system::reachable_addrs;
+/// use std::net::SocketAddr;
+///
+/// let addr: SocketAddr = "192.168.0.10:8080".parse().unwrap();
+/// assert_eq!(reachable_addrs(&addr), vec![addr]);
+///
+/// let addr: SocketAddr = "[::]:8080".parse().unwrap();
+/// assert_eq!(reachable_addrs(&addr)[0].to_string(), "[::1]:8080");
+/// ```
+///
+pub fn reachable_addrs(addr: &SocketAddr) -> Vec<SocketAddr> {
+    if !addr.ip().is_unspecified() {
+        return vec![*addr];
+    }
+
+    let loopback = match addr.ip() {
+        IpAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        IpAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+    };
+
+    let mut addrs = vec![SocketAddr::new(loopback, addr.port())];
+    if let Some(ip) = lan_ip(addr.is_ipv6()) {
+        addrs.push(SocketAddr::new(ip, addr.port()));
+    }
+    addrs
+}
+
+///
+/// The address of the interface used for the default route. A UDP
+/// socket is never actually sent anything on `connect`, so this works
+/// without network access (there's just no route, and no address)
+///
+fn lan_ip(v6: bool) -> Option<IpAddr> {
+    let (local, remote) = match v6 {
+        true => ("[::]:0", "[2001:4860:4860::8888]:80"),
+        false => ("0.0.0.0:0", "8.8.8.8:80"),
+    };
+    let socket = UdpSocket::bind(local).ok()?;
+    socket.connect(remote).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    match ip.is_unspecified() || ip.is_loopback() {
+        true => None,
+        false => Some(ip),
+    }
 }
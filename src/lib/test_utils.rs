@@ -27,7 +27,17 @@ pub fn get_test_proxy<H>(server: &TestServer, handler: H) -> (TestServer, String
 where
     H: Fn(&mut TestApp<AppState>) + Send + Sync + Clone + 'static,
 {
-    let srv_address = server.addr().to_string();
+    get_test_proxy_to(server.addr().to_string(), handler)
+}
+
+///
+/// Helper to create a proxy test server for any address,
+/// even one that nothing is listening on
+///
+pub fn get_test_proxy_to<H>(srv_address: String, handler: H) -> (TestServer, String)
+where
+    H: Fn(&mut TestApp<AppState>) + Send + Sync + Clone + 'static,
+{
     let p = test::TestServer::build_with_state(move || {
         let addr = srv_address.clone();
         let s = AppState::new(addr.clone(), "http");
@@ -0,0 +1,287 @@
+use actix::actors::resolver::ResolverError;
+use actix_web::client::{ClientConnectorError, SendRequestError};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use app_state::AppState;
+use futures::future::ok;
+use futures::Future;
+use har::record_failure;
+use header_rules::HeaderChanges;
+use serde_json;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+///
+/// How many failures are kept for `/__bs/errors.json`
+///
+const MAX_LOGGED_FAILURES: usize = 100;
+
+///
+/// Why a request to the proxy target failed
+///
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    BodyTooLarge,
+    InvalidResponse,
+}
+
+impl FailureKind {
+    pub fn from_send_error(send_error: &SendRequestError) -> FailureKind {
+        match *send_error {
+            SendRequestError::Timeout => FailureKind::Timeout,
+            SendRequestError::ParseError(..) => FailureKind::InvalidResponse,
+            SendRequestError::Connector(ref e) => match *e {
+                ClientConnectorError::SslError(..) => FailureKind::Tls,
+                ClientConnectorError::SslIsNotSupported => FailureKind::Tls,
+                ClientConnectorError::Timeout => FailureKind::Timeout,
+                ClientConnectorError::Resolver(ResolverError::Resolver(..)) => FailureKind::Dns,
+                ClientConnectorError::Resolver(ResolverError::Timeout) => FailureKind::Timeout,
+                _ => FailureKind::Connect,
+            },
+            _ => FailureKind::Connect,
+        }
+    }
+    pub fn from_payload_error(payload_error: &PayloadError) -> FailureKind {
+        match *payload_error {
+            PayloadError::Overflow => FailureKind::BodyTooLarge,
+            _ => FailureKind::InvalidResponse,
+        }
+    }
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            FailureKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            FailureKind::Dns => "The target's host name could not be resolved",
+            FailureKind::Connect => "Could not connect to the target",
+            FailureKind::Tls => "Could not make a secure connection to the target",
+            FailureKind::Timeout => "The target took too long to respond",
+            FailureKind::BodyTooLarge => "The body was too large for the proxy to handle",
+            FailureKind::InvalidResponse => "The target sent a response that could not be read",
+        }
+    }
+    pub fn hint(&self) -> Option<&'static str> {
+        match *self {
+            FailureKind::Tls => Some(
+                "If the certificate is signed by an internal CA, provide the bundle with `--ca-file`. \
+                 Certificates are only verified when `--verify-tls` or `--ca-file` are given.",
+            ),
+            FailureKind::Timeout => Some("The timeout can be raised with `--proxy-timeout-secs`."),
+            _ => None,
+        }
+    }
+}
+
+///
+/// A single failed request to the proxy target
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamFailure {
+    pub method: String,
+    pub url: String,
+    pub kind: FailureKind,
+    pub reason: String,
+    pub elapsed_ms: u64,
+    ///
+    /// Seconds since the unix epoch
+    ///
+    pub time: u64,
+}
+
+impl UpstreamFailure {
+    pub fn new(
+        req: &HttpRequest<AppState>,
+        url: String,
+        kind: FailureKind,
+        reason: String,
+        started: Instant,
+    ) -> UpstreamFailure {
+        let elapsed = started.elapsed();
+        UpstreamFailure {
+            method: req.method().to_string(),
+            url,
+            kind,
+            reason,
+            elapsed_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+///
+/// Turn a failed request to the proxy target into an
+/// error page, recording it along the way
+///
+pub fn send_error_response(
+    req: &HttpRequest<AppState>,
+    url: String,
+    send_error: SendRequestError,
+    started: Instant,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let kind = FailureKind::from_send_error(&send_error);
+    let failure = UpstreamFailure::new(req, url, kind, send_error.to_string(), started);
+    Box::new(ok(failure_response(req, failure)))
+}
+
+///
+/// Record the failure, then respond with HTML for pages,
+/// or JSON for anything else.
+///
+/// The response header rules apply here too, just as they
+/// would have to a response from the target
+///
+pub fn failure_response(req: &HttpRequest<AppState>, failure: UpstreamFailure) -> HttpResponse {
+    debug!("upstream failure = {:?}", failure);
+
+    {
+        let mut log = req
+            .state()
+            .upstream_errors
+            .lock()
+            .expect("should lock & unwrap upstream_errors");
+        if log.len() >= MAX_LOGGED_FAILURES {
+            log.remove(0);
+        }
+        log.push(failure.clone());
+    }
+
+    let mut resp = HttpResponse::build(failure.kind.status());
+
+    let mut resp = match wants_json(req) {
+        true => resp.json(json!({
+            "error": failure.kind.describe(),
+            "hint": failure.kind.hint(),
+            "failure": failure,
+        })),
+        false => resp
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(failure_page(&failure)),
+    };
+
+    HeaderChanges::for_response(req).apply(resp.headers_mut());
+
+    record_failure(req, &failure, &resp);
+
+    resp
+}
+
+///
+/// All recorded failures, oldest first
+///
+pub fn handle_errors_json(req: &HttpRequest<AppState>) -> HttpResponse {
+    let log = req
+        .state()
+        .upstream_errors
+        .lock()
+        .expect("should lock & unwrap upstream_errors");
+
+    match serde_json::to_string_pretty(&*log) {
+        Ok(t) => HttpResponse::Ok().content_type("application/json").body(t),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+///
+/// Requests for our own API, XHR, or anything that
+/// doesn't accept HTML get JSON errors
+///
+fn wants_json(req: &HttpRequest<AppState>) -> bool {
+    let header_value = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase()
+    };
+
+    let is_xhr =
+        header_value(header::HeaderName::from_static("x-requested-with")) == "xmlhttprequest";
+    let accept = header_value(header::ACCEPT);
+    let accepts_html = accept.is_empty() || accept.contains("text/html") || accept.contains("*/*");
+
+    req.path().starts_with("/__bs") || is_xhr || !accepts_html
+}
+
+fn failure_page(failure: &UpstreamFailure) -> String {
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="UTF-8">
+<title>{status} - config-gen</title>
+</head>
+<body>
+    <h1>{description}</h1>
+    <dl>
+        <dt>Request</dt><dd><code>{method} {url}</code></dd>
+        <dt>Failure</dt><dd><code>{kind:?}</code></dd>
+        <dt>Reason</dt><dd><code>{reason}</code></dd>
+        <dt>Elapsed</dt><dd>{elapsed}ms</dd>
+    </dl>
+    <p>{hint}</p>
+    <p>Every failure is listed at <a href="/__bs/errors.json">/__bs/errors.json</a></p>
+</body>
+</html>
+"#,
+        status = failure.kind.status(),
+        description = failure.kind.describe(),
+        method = escape_html(&failure.method),
+        url = escape_html(&failure.url),
+        kind = failure.kind,
+        reason = escape_html(&failure.reason),
+        elapsed = failure.elapsed_ms,
+        hint = escape_html(failure.kind.hint().unwrap_or("")),
+    )
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_kinds() {
+        assert_eq!(
+            FailureKind::from_send_error(&SendRequestError::Timeout),
+            FailureKind::Timeout
+        );
+        assert_eq!(
+            FailureKind::from_send_error(&SendRequestError::Connector(
+                ClientConnectorError::Resolver(ResolverError::Resolver("no such host".into()))
+            )),
+            FailureKind::Dns
+        );
+        assert_eq!(
+            FailureKind::from_payload_error(&PayloadError::Overflow),
+            FailureKind::BodyTooLarge
+        );
+        assert_eq!(FailureKind::Timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(FailureKind::Dns.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="/?a=1&b=2">"#),
+            "&lt;a href=&quot;/?a=1&amp;b=2&quot;&gt;"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use options::{get_host, ProxyScheme};
+use serde::de::{self, Deserialize, Deserializer};
+
+///
+/// Every extra upstream is mounted under this path
+///
+pub const UPSTREAM_PREFIX: &str = "/__bs/upstream";
+
+///
+/// An additional host (such as a CDN, or a static/media subdomain)
+/// that's proxied through the local server under its own prefix.
+///
+/// # Examples
+///
+/// ```yaml
+/// upstreams:
+///   - name: static
+///     url: https://static.acme.com
+/// ```
+///
+/// `https://static.acme.com/js/app.js` is then available
+/// at `/__bs/upstream/static/js/app.js`
+///
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExtraUpstream {
+    pub name: String,
+    pub scheme: ProxyScheme,
+    ///
+    /// The host, including the port when it's not the default one
+    ///
+    pub host: String,
+}
+
+impl ExtraUpstream {
+    pub fn new(name: &str, url: &str) -> Result<ExtraUpstream, String> {
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid_name {
+            return Err(format!(
+                "upstream name `{}` should only contain letters, numbers, `-` or `_`",
+                name
+            ));
+        }
+
+        let (host, scheme, _base_path) =
+            get_host(url).map_err(|e| format!("upstream `{}` has an invalid url, {}", name, e))?;
+
+        Ok(ExtraUpstream {
+            name: name.to_string(),
+            scheme: match scheme.as_str() {
+                "https" => ProxyScheme::Https,
+                _ => ProxyScheme::Http,
+            },
+            host,
+        })
+    }
+    ///
+    /// The local path that this upstream is mounted on
+    ///
+    pub fn prefix(&self) -> String {
+        format!("{}/{}", UPSTREAM_PREFIX, self.name)
+    }
+    pub fn origin(&self) -> String {
+        format!("{}://{}", self.scheme, self.host)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExtraUpstream {
+    fn deserialize<D>(deserializer: D) -> Result<ExtraUpstream, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Raw {
+            name: String,
+            url: String,
+        }
+        let raw = Raw::deserialize(deserializer)?;
+        ExtraUpstream::new(&raw.name, &raw.url).map_err(de::Error::custom)
+    }
+}
+
+///
+/// Find the upstream that a local path belongs to, along
+/// with the path to request from that upstream
+///
+/// # Examples
+///
+/// ```
+/// use bs::upstreams::{find_upstream, ExtraUpstream};
+///
+/// let upstreams = vec![ExtraUpstream::new("static", "https://static.acme.com").unwrap()];
+///
+/// let (upstream, path) = find_upstream(&upstreams, "/__bs/upstream/static/js/app.js").unwrap();
+/// assert_eq!(upstream.host, "static.acme.com");
+/// assert_eq!(path, "/js/app.js");
+///
+/// assert!(find_upstream(&upstreams, "/__bs/upstream/statics/js/app.js").is_none());
+/// assert!(find_upstream(&upstreams, "/js/app.js").is_none());
+/// ```
+///
+pub fn find_upstream<'a, 'b>(
+    upstreams: &'a [ExtraUpstream],
+    path: &'b str,
+) -> Option<(&'a ExtraUpstream, &'b str)> {
+    if !path.starts_with(UPSTREAM_PREFIX) {
+        return None;
+    }
+    upstreams.iter().find_map(|upstream| {
+        let prefix = upstream.prefix();
+        match path.starts_with(&prefix) {
+            true => match &path[prefix.len()..] {
+                "" => Some((upstream, "/")),
+                rest if rest.starts_with('/') => Some((upstream, rest)),
+                _ => None,
+            },
+            false => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_parse_upstreams() {
+        let i = r#"
+- name: static
+  url: https://static.acme.com
+- name: media
+  url: http://localhost:8081/
+        "#;
+        let upstreams: Vec<ExtraUpstream> = serde_yaml::from_str(i).unwrap();
+        assert_eq!(upstreams[0].origin(), "https://static.acme.com");
+        assert_eq!(upstreams[0].prefix(), "/__bs/upstream/static");
+        assert_eq!(upstreams[1].origin(), "http://localhost:8081");
+
+        let invalid =
+            serde_yaml::from_str::<Vec<ExtraUpstream>>("[{name: 'a/b', url: 'http://a.com'}]");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_find_upstream_root() {
+        let upstreams = vec![ExtraUpstream::new("media", "https://media.acme.com").unwrap()];
+        let (_, path) = find_upstream(&upstreams, "/__bs/upstream/media").unwrap();
+        assert_eq!(path, "/");
+    }
+}
@@ -1,15 +1,19 @@
 use actix_web::client::ClientRequestBuilder;
 use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
 use app_state::AppState;
+use futures::future::ok;
 use futures::Future;
-use proxy_transform::create_outgoing;
-use std::time::Duration;
+use proxy_response::{handle_proxy_response, ResponseContext};
+use proxy_transform::upstream_url;
+use std::time::{Duration, Instant};
+use upstream_error::{failure_response, send_error_response, FailureKind, UpstreamFailure};
 
 ///
-/// This case handles incoming POST requests
+/// This case handles incoming requests (of any method)
 /// that contain a body.
 ///
-/// Note: This is not tested in any way with large uploads
+/// Note: This is not tested in any way with large uploads. A body that
+/// can't be read (or is too large) is reported like any other failure
 ///
 pub fn forward_request_with_body(
     incoming_request: &HttpRequest<AppState>,
@@ -18,32 +22,39 @@ pub fn forward_request_with_body(
 ) -> Box<Future<Item = HttpResponse, Error = Error>> {
     let state = incoming_request.state();
     let timeout: u64 = state.opts.proxy_timeout_secs.into();
-    let next_target = incoming_request.state().opts.target.clone();
-    let output = incoming_request
-        .body()
-        .from_err()
-        .and_then(move |incoming_body| {
-            outgoing
-                .body(incoming_body)
-                .unwrap()
-                .send()
-                .timeout(Duration::from_secs(timeout))
-                .map_err(Error::from)
-                .and_then(move |proxy_response| {
-                    proxy_response
-                        .body()
-                        .from_err()
-                        .and_then(move |proxy_response_body| {
-                            Ok(create_outgoing(
-                                &proxy_response.status(),
-                                &proxy_response.headers(),
-                                next_target.to_string(),
-                                req_target,
-                            )
-                            .body(proxy_response_body))
-                        })
-                })
-        });
+    let mut context = ResponseContext::new(incoming_request, req_target);
+    let started = Instant::now();
+    let req = incoming_request.clone();
+    let output = incoming_request.body().then(
+        move |res| -> Box<Future<Item = HttpResponse, Error = Error>> {
+            let incoming_body = match res {
+                Ok(body) => body,
+                Err(e) => {
+                    let kind = FailureKind::from_payload_error(&e);
+                    let failure = UpstreamFailure::new(
+                        &req,
+                        upstream_url(&req),
+                        kind,
+                        e.to_string(),
+                        started,
+                    );
+                    return Box::new(ok(failure_response(&req, failure)));
+                }
+            };
+            context.har.set_request_body(incoming_body.clone());
+            Box::new(
+                outgoing
+                    .body(incoming_body)
+                    .unwrap()
+                    .send()
+                    .timeout(Duration::from_secs(timeout))
+                    .then(move |res| match res {
+                        Ok(proxy_response) => handle_proxy_response(proxy_response, context),
+                        Err(e) => send_error_response(&req, upstream_url(&req), e, started),
+                    }),
+            )
+        },
+    );
 
     Box::new(output)
 }
@@ -0,0 +1,156 @@
+use actix_web::client::{self, ClientResponse};
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::ws;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
+use app_state::AppState;
+use futures::future;
+use futures::Future;
+use options::ProxyScheme;
+use proxy_response::{handle_proxy_response, ResponseContext};
+use proxy_transform::{basic_auth_header, join_cookies, req_target};
+use std::time::{Duration, Instant};
+use upstream_error::send_error_response;
+
+///
+/// Is this a request to upgrade the connection to a WebSocket?
+///
+/// # Examples
+///
+/// ```
+/// # extern crate actix_web;
+/// # extern crate bs;
+/// # use actix_web::http::HeaderMap;
+/// # use bs::ws_proxy::is_websocket_upgrade;
+/// let mut headers = HeaderMap::new();
+/// headers.insert("connection", "keep-alive, Upgrade".parse().unwrap());
+/// assert_eq!(is_websocket_upgrade(&headers), false);
+///
+/// headers.insert("upgrade", "websocket".parse().unwrap());
+/// assert_eq!(is_websocket_upgrade(&headers), true);
+/// ```
+///
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    let websocket = headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value.trim().eq_ignore_ascii_case("websocket")
+        });
+
+    connection_upgrade && websocket
+}
+
+///
+/// Open a WebSocket connection to the proxy target, and once that
+/// succeeds, complete the handshake with the original requester.
+///
+/// The requester's key is passed on, so the target's answer holds for
+/// both of them - including the subprotocol it picked, if any. Frames
+/// are then passed back and forth as raw bytes, untouched, until
+/// either side closes the connection
+///
+pub fn proxy_websocket(
+    original_request: &HttpRequest<AppState>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let state = original_request.state();
+
+    let ws_scheme = match state.opts.scheme {
+        ProxyScheme::Http => "ws",
+        ProxyScheme::Https => "wss",
+    };
+
+    let next_url = format!(
+        "{}://{}{}{}",
+        ws_scheme,
+        state.opts.target,
+        original_request.path(),
+        match original_request.uri().query().as_ref() {
+            Some(q) => format!("?{}", q),
+            None => "".to_string(),
+        }
+    );
+
+    debug!("next_ws_url={}", next_url);
+
+    // The 'host' header is taken from the URL, but unlike plain HTTP
+    // the origin must be a full one, since servers commonly verify it
+    let mut outgoing = client::ClientRequest::build();
+    outgoing
+        .with_connector(state.connector.clone())
+        .upgrade()
+        .uri(next_url.as_str())
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "upgrade")
+        .header(
+            header::ORIGIN,
+            format!("{}://{}", state.opts.scheme, state.opts.target),
+        );
+
+    // Extensions are left out, since they'd change the frames
+    let original_req_headers = original_request.headers();
+    let forwarded = [
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_VERSION,
+        header::SEC_WEBSOCKET_PROTOCOL,
+        header::USER_AGENT,
+    ];
+    for name in forwarded.iter() {
+        for value in original_req_headers.get_all(name).iter() {
+            outgoing.header(name.clone(), value.clone());
+        }
+    }
+
+    if original_req_headers.contains_key(header::COOKIE) {
+        outgoing.header(header::COOKIE, join_cookies(original_req_headers));
+    }
+
+    if let Some(auth) = basic_auth_header(&state.program_config) {
+        outgoing.header(header::AUTHORIZATION, auth);
+    }
+
+    let outgoing = match outgoing.streaming(original_request.payload()) {
+        Ok(outgoing) => outgoing,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let timeout = Duration::from_secs(state.opts.proxy_timeout_secs.into());
+    let started = Instant::now();
+    let req = original_request.clone();
+
+    let output = outgoing.send().timeout(timeout).then(
+        move |res| -> Box<Future<Item = HttpResponse, Error = Error>> {
+            match res {
+                Ok(ref upstream) if upstream.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                    Box::new(future::result(tunnel(&req, upstream)))
+                }
+                // refused, so it's treated like any other response
+                Ok(upstream) => {
+                    let context = ResponseContext::new(&req, req_target(&req));
+                    handle_proxy_response(upstream, context)
+                }
+                Err(e) => send_error_response(&req, next_url, e, started),
+            }
+        },
+    );
+
+    Box::new(output)
+}
+
+///
+/// Complete the requester's handshake, answering with
+/// the subprotocol the target picked (if any)
+///
+fn tunnel(req: &HttpRequest<AppState>, upstream: &ClientResponse) -> Result<HttpResponse, Error> {
+    let mut resp = ws::handshake(req)?;
+    if let Some(protocol) = upstream.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        resp.header(header::SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+    Ok(resp.streaming(upstream.payload()))
+}
@@ -26,9 +26,17 @@ fn main() {
     //
     env_logger::init();
 
-    match ProgramOptions::from_args(&mut std::env::args_os()).and_then(system::create) {
-        Ok((sys, url)) => {
-            println!("{}", url);
+    let started = ProgramOptions::from_args(&mut std::env::args_os()).and_then(|opts| {
+        let base_path = opts.base_path.clone();
+        system::create(opts).map(|(sys, urls)| (sys, urls, base_path))
+    });
+
+    match started {
+        Ok((sys, urls, base_path)) => {
+            for url in urls.iter() {
+                println!("{}{}", url, base_path);
+            }
+            let url = &urls[0];
             println!(
                 "{}/__bs/build.json\t(Generates the configuration needed for the Optimizer)",
                 url
@@ -15,7 +15,7 @@ use bs::system;
 use reqwest::Response;
 use rjs::{RequireJsBuildConfig, RequireJsClientConfig};
 
-type RunResult = Result<(actix::SystemRunner, String), ProgramStartError>;
+type RunResult = Result<(actix::SystemRunner, Vec<String>), ProgramStartError>;
 type ApiResult = Result<(actix::SystemRunner, String, Response), ProgramStartError>;
 
 ///
@@ -191,7 +191,8 @@ where
     F: FnOnce(ApiResult) + 'static,
 {
     run_with_args(args, move |result: RunResult| {
-        let (sys, url) = result.expect("system started");
+        let (sys, urls) = result.expect("system started");
+        let url = urls[0].clone();
         let api1 = format!("{}{}", url, path);
         let res = reqwest::get(api1.as_str()).expect("call config.json api endpoint");
         cb(Ok((sys, url, res)));
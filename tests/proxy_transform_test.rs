@@ -1,20 +1,40 @@
+extern crate actix;
 extern crate actix_web;
 extern crate bs;
+extern crate futures;
 extern crate mime;
+extern crate serde_json;
+extern crate serde_yaml;
 
 //#[macro_use]
 extern crate env_logger;
 extern crate log;
 
+use actix::{Actor, StreamHandler};
 use actix_web::http::header;
+use actix_web::http::ContentEncoding;
+use actix_web::http::Method;
+use actix_web::ws;
+use actix_web::AsyncResponder;
+use actix_web::Error;
 use actix_web::HttpMessage;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
+use bs::content_encoding::{Coder, ContentCoding};
+use bs::har::handle_har;
+use bs::overrides::{handle_override, Override, OVERRIDE_HEADER};
 use bs::proxy_transform::proxy_transform;
 use bs::test_utils::get_resp;
 use bs::test_utils::get_test_proxy;
+use bs::test_utils::get_test_proxy_to;
 use bs::test_utils::get_test_server;
+use bs::upstream_error::handle_errors_json;
+use futures::{Future, Stream};
 use mime::{TEXT_HTML, TEXT_HTML_UTF_8};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::time::Duration;
 
 fn test_str(adr: impl Into<String>) -> String {
     format!(
@@ -70,6 +90,108 @@ fn test_replace_links() {
     assert_eq!(resp_body, expected_body);
 }
 
+#[test]
+fn test_replace_links_gzip() {
+    let (target, target_addr) = get_test_server(|app| {
+        app.handler(|req: &HttpRequest| {
+            let srv_address = req
+                .headers()
+                .get("srv_address")
+                .expect("missing srv_address header")
+                .to_str()
+                .expect("headervalue -> str");
+
+            let mut encoder = Coder::encoder(ContentCoding::Gzip);
+            let mut body = encoder
+                .write(test_str(srv_address).as_bytes())
+                .expect("gzip body");
+            body.extend(encoder.finish().expect("finish gzip body"));
+
+            HttpResponse::Ok()
+                .header(header::CONTENT_TYPE, TEXT_HTML_UTF_8)
+                .header(header::CONTENT_ENCODING, "gzip")
+                .content_encoding(ContentEncoding::Identity)
+                .body(body)
+        });
+    });
+
+    let (mut proxy, proxy_address) = get_test_proxy(&target, |app| {
+        app.handler(proxy_transform);
+    });
+
+    let request = proxy
+        .get()
+        .header(header::ACCEPT, TEXT_HTML)
+        .header("srv_address", target_addr)
+        .uri(proxy.url("/"))
+        .set_header(header::HOST, proxy_address.clone())
+        .finish()
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+
+    assert_eq!(
+        resp.headers()
+            .get(header::CONTENT_ENCODING)
+            .expect("has content-encoding"),
+        "gzip"
+    );
+    assert_eq!(resp_body, test_str(proxy_address.clone()));
+}
+
+#[test]
+fn test_forward_put_body() {
+    let (target, target_addr) = get_test_server(|app| {
+        app.handler(
+            |req: &HttpRequest| -> Box<Future<Item = HttpResponse, Error = Error>> {
+                let srv_address = req
+                    .headers()
+                    .get("srv_address")
+                    .expect("missing srv_address header")
+                    .to_str()
+                    .expect("headervalue -> str")
+                    .to_string();
+
+                req.body()
+                    .from_err()
+                    .and_then(move |body| {
+                        let echo = str::from_utf8(&body[..]).expect("bytes->str");
+                        Ok(HttpResponse::Ok()
+                            .content_type("application/json")
+                            .body(format!(
+                                r#"{{"url":"http://{}/cart","echo":{}}}"#,
+                                srv_address, echo
+                            )))
+                    })
+                    .responder()
+            },
+        );
+    });
+
+    let (mut proxy, proxy_address) = get_test_proxy(&target, |app| {
+        app.handler(proxy_transform);
+    });
+
+    let request = proxy
+        .client(Method::PUT, "/rest/V1/carts/mine")
+        .header("srv_address", target_addr)
+        .set_header(header::HOST, proxy_address.clone())
+        .content_type("application/json")
+        .body(r#"{"qty":2}"#)
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp_body,
+        format!(
+            r#"{{"url":"http://{}/cart","echo":{{"qty":2}}}}"#,
+            proxy_address
+        )
+    );
+}
+
 #[test]
 fn test_redirect() {
     let (target, target_addr) = get_test_server(|app| {
@@ -87,7 +209,7 @@ fn test_redirect() {
         });
     });
 
-    let (mut proxy, _proxy_address) = get_test_proxy(&target, |app| {
+    let (mut proxy, proxy_address) = get_test_proxy(&target, |app| {
         app.handler(proxy_transform);
     });
 
@@ -102,12 +224,281 @@ fn test_redirect() {
     let (resp, _resp_body) = get_resp(&mut proxy, request);
     assert_eq!(resp.status(), 302);
 
-    //    let _actual_redirect = resp
-    //        .headers()
-    //        .get(header::LOCATION)
-    //        .expect("has location header")
-    //        .to_str()
-    //        .expect("header->str");
-    //    let expected_redirect = format!("http://{}/login", proxy_address);
-    //    assert_eq!(actual_redirect, expected_redirect);
+    let actual_redirect = resp
+        .headers()
+        .get(header::LOCATION)
+        .expect("has location header")
+        .to_str()
+        .expect("header->str");
+    let expected_redirect = format!("http://{}/login", proxy_address);
+    assert_eq!(actual_redirect, expected_redirect);
+}
+
+struct Echo;
+
+impl Actor for Echo {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for Echo {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        if let ws::Message::Text(text) = msg {
+            ctx.text(text)
+        }
+    }
+}
+
+#[test]
+fn test_websocket() {
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|req: &HttpRequest| ws::start(req, Echo));
+    });
+
+    let (mut proxy, _proxy_address) = get_test_proxy(&target, |app| {
+        app.handler(proxy_transform);
+    });
+
+    let (reader, mut writer) = proxy.ws().expect("websocket connected via proxy");
+    writer.text("hello");
+
+    let (item, _reader) = proxy
+        .execute(reader.into_future())
+        .expect("echo from target");
+    assert_eq!(item, Some(ws::Message::Text("hello".to_string())));
+}
+
+///
+/// Complete a WebSocket handshake through the proxy by hand,
+/// giving back the head of the response
+///
+fn ws_handshake(proxy_address: &str, protocols: &str) -> String {
+    let mut stream = TcpStream::connect(proxy_address).expect("connected to proxy");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("set timeout");
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        proxy_address, protocols
+    )
+    .expect("sent handshake");
+
+    let mut head = vec![];
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream
+            .read_exact(&mut byte)
+            .expect("read handshake response");
+        head.push(byte[0]);
+    }
+    String::from_utf8_lossy(&head).to_lowercase()
+}
+
+#[test]
+fn test_websocket_protocol() {
+    // picks the second of the protocols that are offered
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|req: &HttpRequest| -> Result<HttpResponse, Error> {
+            let mut resp = ws::handshake(req)?;
+            resp.header(header::SEC_WEBSOCKET_PROTOCOL, "graphql-transport-ws");
+            let stream = ws::WsStream::new(req.payload());
+            Ok(resp.body(ws::WebsocketContext::create(req.clone(), Echo, stream)))
+        });
+    });
+
+    let (_proxy, proxy_address) = get_test_proxy(&target, |app| {
+        app.handler(proxy_transform);
+    });
+
+    let head = ws_handshake(&proxy_address, "graphql-ws, graphql-transport-ws");
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(
+        head.contains("sec-websocket-protocol: graphql-transport-ws\r\n"),
+        "{}",
+        head
+    );
+}
+
+#[test]
+fn test_websocket_no_protocol() {
+    // accepts without choosing any of them
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|req: &HttpRequest| ws::start(req, Echo));
+    });
+
+    let (_proxy, proxy_address) = get_test_proxy(&target, |app| {
+        app.handler(proxy_transform);
+    });
+
+    let head = ws_handshake(&proxy_address, "graphql-ws");
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(!head.contains("sec-websocket-protocol"), "{}", head);
+}
+
+#[test]
+fn test_upstream_failure() {
+    // bind, then drop, to get an address that nothing is listening on
+    let closed_address = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("local address")
+        .to_string();
+
+    let (mut proxy, _proxy_address) = get_test_proxy_to(closed_address.clone(), |app| {
+        app.resource("/__bs/errors.json", |r| r.f(handle_errors_json));
+        app.handler(proxy_transform);
+    });
+
+    let request = proxy
+        .get()
+        .header(header::ACCEPT, TEXT_HTML)
+        .uri(proxy.url("/checkout"))
+        .finish()
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+    assert_eq!(resp.status(), 502);
+    assert!(resp_body.contains("Could not connect to the target"));
+
+    let request = proxy
+        .get()
+        .uri(proxy.url("/__bs/errors.json"))
+        .finish()
+        .expect("finish request");
+
+    let (.., resp_body) = get_resp(&mut proxy, request);
+    let failures: serde_json::Value = serde_json::from_str(&resp_body).expect("errors json");
+    assert_eq!(failures[0]["kind"], "connect");
+    assert_eq!(
+        failures[0]["url"],
+        format!("http://{}/checkout", closed_address)
+    );
+}
+
+#[test]
+fn test_request_body_too_large() {
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|_req: &HttpRequest| HttpResponse::Ok().body("saved"));
+    });
+
+    let (mut proxy, _proxy_address) = get_test_proxy(&target, |app| {
+        app.resource("/__bs/errors.json", |r| r.f(handle_errors_json));
+        app.handler(proxy_transform);
+    });
+
+    let request = proxy
+        .post()
+        .header(header::ACCEPT, TEXT_HTML)
+        .uri(proxy.url("/customer/account/upload"))
+        .body(vec![b'a'; 1_000_000])
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+    assert_eq!(resp.status(), 502);
+    assert!(resp_body.contains("The body was too large for the proxy to handle"));
+
+    let request = proxy
+        .get()
+        .uri(proxy.url("/__bs/errors.json"))
+        .finish()
+        .expect("finish request");
+
+    let (.., resp_body) = get_resp(&mut proxy, request);
+    let failures: serde_json::Value = serde_json::from_str(&resp_body).expect("errors json");
+    assert_eq!(failures[0]["kind"], "body_too_large");
+    assert_eq!(failures[0]["method"], "POST");
+}
+
+#[test]
+fn test_har() {
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|_req: &HttpRequest| {
+            HttpResponse::Ok()
+                .content_type("text/plain")
+                .body("hello world")
+        })
+    });
+
+    let (mut proxy, _proxy_address) = get_test_proxy(&target, |app| {
+        app.resource("/__bs/har", |r| r.f(handle_har));
+        app.handler(proxy_transform);
+    });
+
+    let request = proxy
+        .get()
+        .uri(proxy.url("/catalog?page=2"))
+        .finish()
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp_body, "hello world");
+
+    let request = proxy
+        .get()
+        .uri(proxy.url("/__bs/har"))
+        .finish()
+        .expect("finish request");
+
+    let (.., resp_body) = get_resp(&mut proxy, request);
+    let har: serde_json::Value = serde_json::from_str(&resp_body).expect("har json");
+    let entry = &har["log"]["entries"][0];
+    assert_eq!(entry["request"]["method"], "GET");
+    assert_eq!(entry["request"]["queryString"][0]["value"], "2");
+    assert_eq!(
+        entry["request"]["_upstreamUrl"],
+        format!("http://{}/catalog?page=2", target.addr())
+    );
+    assert_eq!(entry["response"]["status"], 200);
+    assert_eq!(entry["response"]["_upstream"]["status"], 200);
+}
+
+#[test]
+fn test_overrides() {
+    let (target, _target_addr) = get_test_server(|app| {
+        app.handler(|_req: &HttpRequest| {
+            HttpResponse::Ok()
+                .content_type("application/javascript")
+                .body("console.log(\"target\");")
+        })
+    });
+
+    let (mut proxy, _proxy_address) = get_test_proxy(&target, |app| {
+        let item: Override =
+            serde_yaml::from_str("{path: /static/*, dir: test/fixtures}").expect("override");
+        app.resource(&item.route(), move |r| {
+            r.f(move |req| handle_override(req, &item))
+        });
+    });
+
+    let request = proxy
+        .get()
+        .uri(proxy.url("/static/override.js"))
+        .finish()
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp_body, "console.log(\"overridden\");\n");
+    assert_eq!(
+        resp.headers().get(OVERRIDE_HEADER).unwrap(),
+        "test/fixtures/override.js"
+    );
+
+    // files that don't exist locally come from the target
+    let request = proxy
+        .get()
+        .uri(proxy.url("/static/missing.js"))
+        .finish()
+        .expect("finish request");
+
+    let (resp, resp_body) = get_resp(&mut proxy, request);
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp_body, "console.log(\"target\");");
+    assert!(resp.headers().get(OVERRIDE_HEADER).is_none());
 }